@@ -0,0 +1,143 @@
+use crate::config::{AdditionalArchive, Config};
+use crate::keep_open::{pause_if_failed, pause_on_failure};
+use crate::remote::{Remote, RemoteCommand};
+use crate::{unique_name, Args, StagedUpload};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, OptionExt};
+use color_eyre::Result;
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const UPLOAD_EXPLANATION: &str = "Upload an additional archive, to be extracted on the target.";
+
+/// Deploys the `additional_archives`, in the order they are configured.
+///
+/// Uploading is done over a second connection, in a separate thread. So the next archive is
+/// uploaded while the previous one is extracted.
+pub fn deploy_additional_archives(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    let archives = &config.additional_archives;
+    if archives.is_empty() {
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    if remote.is_plan() {
+        for archive in archives {
+            let upload = StagedUpload::with_unique_path(remote, upload_path(config, archive)?);
+            remote.upload_file(&archive.zip, upload.path(), UPLOAD_EXPLANATION)?;
+            extract(config, remote, archive, upload.path())?;
+        }
+        return Ok(());
+    }
+
+    let mut extract_time = Duration::ZERO;
+    let stop_uploading = AtomicBool::new(false);
+    thread::scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let stop_uploading = &stop_uploading;
+        let upload_thread = scope.spawn(move || {
+            let uploads = match Remote::connect(config, None) {
+                Ok(uploads) => uploads,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return None;
+                }
+            };
+            for (i, archive) in archives.iter().enumerate() {
+                // Extracting failed, then there is no use in uploading the rest.
+                if stop_uploading.load(Ordering::Relaxed) {
+                    break;
+                }
+                let uploaded = upload_path(config, archive).and_then(|remote_zip_path| {
+                    uploads.upload_file(&archive.zip, &remote_zip_path, UPLOAD_EXPLANATION)?;
+                    info!("[{}/{}] Uploaded `{}`", i + 1, archives.len(), archive.zip);
+                    Ok(remote_zip_path)
+                });
+                if sender.send(uploaded).is_err() {
+                    break;
+                }
+            }
+            Some(uploads)
+        });
+
+        let extracted = archives
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, archive)| -> Result<()> {
+                let upload = receiver
+                    .recv()
+                    .map_err(|_| eyre!("Uploading `{}` stopped unexpectedly", archive.zip))??;
+                let upload = StagedUpload::with_unique_path(remote, upload);
+
+                let extract_start = Instant::now();
+                let extracted = extract(config, remote, archive, upload.path());
+                pause_if_failed(config, args, remote, extracted)?;
+                extract_time += extract_start.elapsed();
+                info!(
+                    "[{}/{}] Extracted `{}` to `{}`",
+                    i + 1,
+                    archives.len(),
+                    archive.zip,
+                    archive.target_directory
+                );
+                Ok(())
+            });
+
+        // Removes the archives that were uploaded, but not extracted because extracting failed.
+        if let Err(e) = &extracted {
+            pause_on_failure(config, args, remote, e);
+        }
+        stop_uploading.store(true, Ordering::Relaxed);
+        for upload in receiver.iter().flatten() {
+            drop(StagedUpload::with_unique_path(remote, upload));
+        }
+        if let Ok(Some(uploads)) = upload_thread.join() {
+            remote.add_stats(uploads);
+        }
+        extracted
+    })?;
+
+    info!(
+        "Deployed {} additional archive(s) in {:.1}s, of which {:.1}s extracting",
+        archives.len(),
+        start.elapsed().as_secs_f64(),
+        extract_time.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// A name of its own for every upload. Archives can have the same file name, and the next one
+/// is uploaded while the previous one is still being extracted.
+fn upload_path(config: &Config, archive: &AdditionalArchive) -> Result<Utf8PathBuf> {
+    let name = archive
+        .zip
+        .file_name()
+        .ok_or_eyre("Cannot upload file, path does not have file name.")?;
+    Ok(config.upload_directory().join(unique_name(name)?))
+}
+
+fn extract(
+    config: &Config,
+    remote: &Remote,
+    archive: &AdditionalArchive,
+    remote_zip_path: &Utf8Path,
+) -> Result<()> {
+    let target = &archive.target_directory;
+    let user = &config.rss_r_production_user;
+
+    remote.execute_command(
+        RemoteCommand::new(
+            format!("sudo unzip -o '{remote_zip_path}' -d '{target}'"),
+            "Extract the additional archive over its target directory.",
+        )
+        .allow_unzip_warnings()
+        .destructive(),
+    )?;
+    remote.execute_command(RemoteCommand::new(
+        format!("sudo chown -R '{user}':'{user}' '{target}'"),
+        "Give the extracted files to the user the service runs as.",
+    ))
+}
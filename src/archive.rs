@@ -0,0 +1,154 @@
+use camino::Utf8Path;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use zip::ZipArchive;
+
+/// The top directory in the zip should be `rss_r`, and the executable is also called `rss_r`.
+pub const RSS_R_EXEC_IN_ZIP: &str = "rss_r/rss_r";
+pub const STATIC_DIR_IN_ZIP: &str = "rss_r/static/";
+
+/// A file in a zip, without reading its contents.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ZipEntry {
+    /// Uncompressed size in bytes.
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// Returns the SHA-256 of a local file, as a lowercase hex string.
+/// Same format as the output of `sha256sum`.
+pub fn sha256_file(path: &Utf8Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Could not open `{}`", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Could not read `{}`", path))?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the SHA-256 of a file inside a local zip, as a lowercase hex string.
+pub fn sha256_zip_entry(path: &Utf8Path, name: &str) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Could not open `{}`", path))?;
+    let mut archive =
+        ZipArchive::new(file).with_context(|| format!("`{}` is not a valid zip", path))?;
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("`{}` does not contain `{}`", path, name))?;
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut entry, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the SHA-256 of every file in a local zip, by their path in the zip. Directories are
+/// left out.
+pub fn sha256_zip_entries(path: &Utf8Path) -> Result<BTreeMap<String, String>> {
+    let file = File::open(path).with_context(|| format!("Could not open `{}`", path))?;
+    let mut archive =
+        ZipArchive::new(file).with_context(|| format!("`{}` is not a valid zip", path))?;
+
+    let mut hashes = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let mut hasher = Sha256::new();
+        io::copy(&mut entry, &mut hasher)?;
+        hashes.insert(entry.name().to_string(), format!("{:x}", hasher.finalize()));
+    }
+
+    Ok(hashes)
+}
+
+/// Lists the files in a local zip, by their path in the zip. Directories are left out.
+pub fn list_zip_files(path: &Utf8Path) -> Result<BTreeMap<String, ZipEntry>> {
+    let file = File::open(path).with_context(|| format!("Could not open `{}`", path))?;
+    let mut archive =
+        ZipArchive::new(file).with_context(|| format!("`{}` is not a valid zip", path))?;
+
+    let mut files = BTreeMap::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        files.insert(
+            entry.name().to_string(),
+            ZipEntry {
+                size: entry.size(),
+                crc32: entry.crc32(),
+            },
+        );
+    }
+
+    Ok(files)
+}
+
+/// Logs which files were added, removed or changed in the `new` zip compared to the `old` one.
+pub fn log_zip_differences(old: &Utf8Path, new: &Utf8Path) -> Result<()> {
+    let old_files = list_zip_files(old)?;
+    let new_files = list_zip_files(new)?;
+
+    info!("Comparing `{}` against `{}`", new, old);
+
+    let mut differences = 0;
+    for (name, new_entry) in &new_files {
+        match old_files.get(name) {
+            None => {
+                info!("Added: `{}` ({} bytes)", name, new_entry.size);
+                differences += 1;
+            }
+            Some(old_entry) if old_entry.size != new_entry.size => {
+                info!(
+                    "Changed: `{}` ({} -> {} bytes)",
+                    name, old_entry.size, new_entry.size
+                );
+                differences += 1;
+            }
+            Some(old_entry) if old_entry.crc32 != new_entry.crc32 => {
+                info!("Changed: `{}` (same size)", name);
+                differences += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, old_entry) in &old_files {
+        if !new_files.contains_key(name) {
+            info!("Removed: `{}` ({} bytes)", name, old_entry.size);
+            differences += 1;
+        }
+    }
+
+    info!("{} file(s) differ", differences);
+
+    match (
+        old_files.get(RSS_R_EXEC_IN_ZIP),
+        new_files.get(RSS_R_EXEC_IN_ZIP),
+    ) {
+        (_, None) => warn!(
+            "The new zip does not contain the `{}` executable!",
+            RSS_R_EXEC_IN_ZIP
+        ),
+        (Some(old_exec), Some(new_exec)) if old_exec == new_exec => {
+            info!("The executable is unchanged")
+        }
+        _ => warn!("The executable changed"),
+    }
+    if !new_files
+        .keys()
+        .any(|name| name.starts_with(STATIC_DIR_IN_ZIP))
+    {
+        warn!(
+            "The new zip does not contain any files in `{}`!",
+            STATIC_DIR_IN_ZIP
+        );
+    }
+
+    Ok(())
+}
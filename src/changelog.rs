@@ -0,0 +1,127 @@
+use crate::config::Config;
+use crate::confirm::confirm;
+use crate::deploy_info::{DeployInfo, DEPLOY_INFO_FILE};
+use crate::remote::{Remote, RemoteCommand};
+use crate::sudo_cat_if_exists;
+use camino::Utf8Path;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::{info, warn};
+use std::process::Command;
+
+/// Reads the `DEPLOY_INFO` file from the target. `None` if there was no production deploy yet,
+/// or when only planning.
+pub fn read_deployed_info(config: &Config, remote: &Remote) -> Result<Option<DeployInfo>> {
+    let deploy_info_path = config.rss_r_production_directory.join(DEPLOY_INFO_FILE);
+
+    let contents = remote.execute_command_capture(RemoteCommand::new(
+        sudo_cat_if_exists(&deploy_info_path),
+        "Read the record of the last deploy.",
+    ))?;
+    if contents.trim().is_empty() {
+        return Ok(None);
+    }
+
+    DeployInfo::from_ron(&contents).map(Some)
+}
+
+/// Logs what is about to be deployed, compared to what is deployed now. With the git log in
+/// between, if both are git commits of the local repository.
+/// Then asks whether to continue, unless `skip_confirmation`.
+pub fn confirm_changes_since_last_deploy(
+    config: &Config,
+    remote: &Remote,
+    skip_confirmation: bool,
+) -> Result<()> {
+    let deployed = read_deployed_info(config, remote)?;
+    if remote.is_plan() {
+        return Ok(());
+    }
+    let new = DeployInfo::for_deploy(config)?;
+
+    match deployed {
+        None => info!("Nothing was deployed yet, deploying {}", describe(&new)),
+        Some(old) if old.zip_sha256 == new.zip_sha256 => warn!(
+            "Redeploying the same zip as the last deploy: {}",
+            describe(&old)
+        ),
+        Some(old) => {
+            info!("Deploying {} over {}", describe(&new), describe(&old));
+            if let (Some(old_label), Some(new_label)) = (&old.label, &new.label) {
+                log_git_history(&config.rss_r_zip, old_label, new_label);
+            }
+        }
+    }
+
+    if skip_confirmation {
+        return Ok(());
+    }
+    if confirm("Continue with the deploy?")? {
+        Ok(())
+    } else {
+        Err(eyre!("Deploy cancelled"))
+    }
+}
+
+fn describe(deploy_info: &DeployInfo) -> String {
+    // The full hash is a bit long for a summary, this is plenty to tell zips apart.
+    let sha = &deploy_info.zip_sha256[..deploy_info.zip_sha256.len().min(12)];
+    match &deploy_info.label {
+        Some(label) => format!("`{label}` (zip {sha})"),
+        None => format!("zip {sha}"),
+    }
+}
+
+/// Logs the commits between the deployed and the new version. Does nothing if the labels are
+/// not commits in the repository the zip is in.
+fn log_git_history(zip: &Utf8Path, old: &str, new: &str) {
+    let Some(dir) = zip.parent().filter(|dir| !dir.as_str().is_empty()) else {
+        return;
+    };
+
+    let range = if is_ancestor(dir, old, new) == Some(true) {
+        info!("Commits since the last deploy:");
+        format!("{old}..{new}")
+    } else if is_ancestor(dir, new, old) == Some(true) {
+        warn!("The new version is OLDER than the deployed one! These commits would be undone:");
+        format!("{new}..{old}")
+    } else {
+        if is_ancestor(dir, old, new).is_some() {
+            warn!("The new version is not based on the deployed one, their histories diverged");
+        }
+        return;
+    };
+
+    let Ok(output) = Command::new("git")
+        .args(["-C", dir.as_str(), "log", "--oneline", &range])
+        .output()
+    else {
+        return;
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("  {}", line);
+    }
+}
+
+/// `None` if git could not tell, for example because one of them is not a commit.
+fn is_ancestor(dir: &Utf8Path, ancestor: &str, descendant: &str) -> Option<bool> {
+    let status = Command::new("git")
+        .args([
+            "-C",
+            dir.as_str(),
+            "merge-base",
+            "--is-ancestor",
+            ancestor,
+            descendant,
+        ])
+        .output()
+        .ok()?
+        .status;
+
+    // Exits with 0 if it is an ancestor, 1 if not, and something else on errors.
+    match status.code() {
+        Some(0) => Some(true),
+        Some(1) => Some(false),
+        _ => None,
+    }
+}
@@ -1,7 +1,9 @@
+use crate::REMOTE_TEMP_DIR;
 use camino::Utf8PathBuf;
 use log::info;
 use ron::ser::{to_string_pretty, PrettyConfig};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::path::PathBuf;
 
@@ -13,25 +15,141 @@ pub const CONFIG_FILE: &str = "deploy_config.ron";
 #[serde(default)]
 pub struct Config {
     /// This is the host the rss_r program will be deployed to.
-    /// Either hostname, or ip.
+    /// Either hostname, or ip. IPv6 addresses can be written with or without brackets.
     pub target_host: String,
+    /// Port of the ssh server on the target. Ignored when `target_host` has a port, like
+    /// `example.com:2222`.
     pub target_ip: u32,
+    /// Seconds to wait for the target to accept the connection, and to finish the ssh handshake.
+    pub connect_timeout_secs: u64,
+    /// How many times to upload the zip again when its SHA-256 on the target does not match,
+    /// before giving up. 0 means the SHA-256 is not checked, except with `--resume-upload`.
+    pub max_upload_retries: u32,
     /// Username to log in as on the target.
     pub username: String,
+    /// Shell to run the remote commands with, for example `/bin/sh`. The commands are run as
+    /// `<shell> -c '<command>'`, which keeps their quoting working when the login shell of the
+    /// user is not a POSIX shell (like `fish`). Empty means the login shell is used directly.
+    pub remote_shell: String,
+    /// Whether `sudo` on the target asks for a password. If so, it is asked once at the start of
+    /// a production deploy. Needs `Defaults:<username> timestamp_type=global` in the sudoers on
+    /// the target, so the password is remembered for all the commands that follow.
+    pub sudo_requires_password: bool,
+    /// Name of a systemd-nspawn container on the target (as listed by `machinectl`) to deploy
+    /// into. The commands are then run inside the container, as `username`, with
+    /// `sudo systemd-run --machine`. Empty means the commands are run on the target itself.
+    pub remote_container: String,
+    /// Directory on the target that files are uploaded to, before they are moved into place.
+    /// With a `remote_container`, this must be bind-mounted into the container at the same
+    /// path, because uploads always land on the target itself. Empty means `/tmp`, which is
+    /// only allowed without a `remote_container`.
+    pub upload_directory: Utf8PathBuf,
 
     /// Local zip file that contains the built `rss_r` executable and `resources` direcory.
     pub rss_r_zip: Utf8PathBuf,
+    /// Minisign public key, like `RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3`.
+    /// When set, the zip is only deployed if it has a valid signature from this key. So are the
+    /// `additional_archives`, with their signature in `<zip>.minisig`.
+    pub archive_public_key: String,
+    /// Signature of the zip. Empty means `<rss_r_zip>.minisig`.
+    pub archive_signature: Utf8PathBuf,
     /// Directory on the target that the rss_r script will be deployed to in test mode.
     /// This directory will be emptied upon test deployment.
     pub rss_r_target_test_dir: Utf8PathBuf,
 
     /// File that will become the `app_config.ron` file when rss_r is being tested on target.
     pub rss_r_test_config_file: Utf8PathBuf,
+    /// Environment variables to run the test instance with, when using `--run`.
+    /// For example `{"RUST_LOG": "debug"}`.
+    pub test_run_env: BTreeMap<String, String>,
+    /// Permissions of the directories created on the target, in octal. Those of the test
+    /// instance, and on a first deploy the production and persistence directories.
+    /// For example `"700"`, so the persistence directory is not readable by other users.
+    /// Empty means the remote umask decides.
+    /// Not used for the production static directory, which may need to be readable by
+    /// a web server. Which then also needs to get through the production directory.
+    pub created_dir_mode: String,
 
     /// This is the directory where the production `rss_r` executable and `static` folder are located.
     pub rss_r_production_directory: Utf8PathBuf,
+    /// File that will become the `persistence/app_config.ron` file in production.
+    /// Before it is overwritten, the difference with the live one is shown for confirmation,
+    /// and the live one is backed up. Empty means the production config is left alone.
+    pub rss_r_production_config_file: Utf8PathBuf,
+    /// Name of the systemd service that runs rss_r in production.
+    pub service_name: String,
+    /// Systemd unit file to install as `/etc/systemd/system/<service_name>.service` on a
+    /// production deploy. Empty means the unit file from the zip is used, if the zip has a
+    /// `rss_r/<service_name>.service`. Otherwise the unit file is left alone.
+    pub deploy_unit_file: Utf8PathBuf,
+    /// When the executable did not change, reload the service with `systemctl reload` instead
+    /// of stopping and starting it. So the static files and config are picked up without
+    /// downtime. The static files are briefly missing while they are replaced.
+    /// Falls back to a restart if the service does not support reloading.
+    pub reload_instead_of_restart: bool,
     /// Username / group given to the uploaded files in production. As in with: `chown name:name file`.
     pub rss_r_production_user: String,
+    /// Where the `static` directory is deployed to in production. For when it is served
+    /// from somewhere else than next to the executable. This directory will be emptied upon
+    /// production deployment.
+    /// When left empty, it is `<rss_r_production_directory>/static`.
+    pub rss_r_production_static_directory: Utf8PathBuf,
+    /// Files in the static directory of the zip that should not be deployed to production.
+    /// For example source maps: `"*.map"`. Patterns are relative to the static directory, and
+    /// `*` also matches across directories. `?` and `[...]` can be used too, like in `unzip`.
+    /// Empty means everything gets deployed.
+    pub static_exclude_globs: Vec<String>,
+    /// Memory that should be available on the target before the service is started, in MiB.
+    /// 0 means it is not checked.
+    pub min_available_memory_mib: u64,
+    /// When less than `min_available_memory_mib` is available, don't start the service. When
+    /// false, only warn.
+    pub abort_on_low_memory: bool,
+    /// Hold a lock with `flock` on `.deploy.lock` in the production directory, for the whole
+    /// production deploy. So the target itself keeps deploys from different machines apart,
+    /// and from manual operations that take the same lock.
+    pub remote_lock: bool,
+    /// How long to wait for the `remote_lock`, before failing the deploy.
+    pub remote_lock_timeout_secs: u64,
+    /// URL to check after a production deploy, from the target itself. For example
+    /// `http://localhost:8000/`. The deploy is rolled back if it does not answer with a 2xx
+    /// status in time. Empty means there is no health check.
+    pub health_check_url: String,
+    /// Permissions the production executable should have, checked with `--verify-permissions`.
+    /// Empty means it is not checked.
+    pub executable_mode: String,
+    /// Permission bits that no file in the production static directory should have, checked
+    /// with `--verify-permissions`. The default `"022"` is writable by group or others.
+    /// Empty means it is not checked.
+    pub static_forbidden_permissions: String,
+    /// Permission bits that nothing in the production `persistence` directory should have,
+    /// checked with `--verify-permissions`. The default `"004"` is readable by others.
+    /// Empty means it is not checked.
+    pub persistence_forbidden_permissions: String,
+    /// How many times to try the health check, before giving up.
+    pub health_check_retries: u32,
+    /// Seconds to wait between health check attempts.
+    pub health_check_interval_secs: u64,
+
+    /// More archives to deploy to production, next to `rss_r_zip`. Like separately versioned
+    /// plugin bundles. They are extracted in this order, while the service is stopped.
+    /// They are not rolled back if the new version fails to start.
+    pub additional_archives: Vec<AdditionalArchive>,
+
+    /// Recorded in the `DEPLOY_INFO` file after a production deploy.
+    /// When left empty, the git commit of the repository the zip is in is used, if there is one.
+    pub deploy_label: String,
+}
+
+/// An archive that is extracted as-is into a directory on the target.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdditionalArchive {
+    /// Local zip file.
+    pub zip: Utf8PathBuf,
+    /// Absolute directory on the target to extract the zip into. Existing files are
+    /// overwritten, other files are left alone.
+    pub target_directory: Utf8PathBuf,
 }
 
 impl Default for Config {
@@ -39,12 +157,40 @@ impl Default for Config {
         Config {
             target_host: String::new(),
             target_ip: 22,
+            connect_timeout_secs: 10,
+            max_upload_retries: 0,
             username: String::new(),
+            remote_shell: String::new(),
+            sudo_requires_password: false,
+            remote_container: String::new(),
+            upload_directory: Utf8PathBuf::new(),
             rss_r_zip: Utf8PathBuf::new(),
+            archive_public_key: String::new(),
+            archive_signature: Utf8PathBuf::new(),
             rss_r_target_test_dir: Utf8PathBuf::new(),
             rss_r_test_config_file: Utf8PathBuf::new(),
+            test_run_env: BTreeMap::new(),
+            created_dir_mode: String::new(),
             rss_r_production_directory: Utf8PathBuf::new(),
+            rss_r_production_config_file: Utf8PathBuf::new(),
+            service_name: "rss_r".to_string(),
+            deploy_unit_file: Utf8PathBuf::new(),
+            reload_instead_of_restart: false,
             rss_r_production_user: String::new(),
+            rss_r_production_static_directory: Utf8PathBuf::new(),
+            static_exclude_globs: Vec::new(),
+            additional_archives: Vec::new(),
+            min_available_memory_mib: 0,
+            abort_on_low_memory: true,
+            remote_lock: false,
+            remote_lock_timeout_secs: 60,
+            health_check_url: String::new(),
+            executable_mode: "755".to_string(),
+            static_forbidden_permissions: "022".to_string(),
+            persistence_forbidden_permissions: "004".to_string(),
+            health_check_retries: 10,
+            health_check_interval_secs: 3,
+            deploy_label: String::new(),
         }
     }
 }
@@ -58,6 +204,33 @@ impl Config {
         fs::write(&path, serialized).expect("Could not save config file");
     }
 
+    /// Saves the config, but only if the file on disk is missing some of the fields this script
+    /// knows about. Saving loses any comments and formatting in the file, so we don't want to
+    /// do that when nothing would be added.
+    pub fn save_if_fields_missing(&self) {
+        let serialized = to_string_pretty(self, PrettyConfig::default())
+            .expect("Could not convert config to RON");
+
+        let Some(our_fields) = field_names(&serialized) else {
+            return;
+        };
+        let file_fields = fs::read_to_string(CONFIG_FILE)
+            .ok()
+            .and_then(|contents| field_names(&contents))
+            .unwrap_or_default();
+
+        let new_fields: Vec<_> = our_fields.difference(&file_fields).cloned().collect();
+        if new_fields.is_empty() {
+            return;
+        }
+
+        self.save();
+        info!(
+            "Config file updated with new fields: {}",
+            new_fields.join(", ")
+        );
+    }
+
     pub fn load() -> Option<Self> {
         info!("Loading configuration from `{}`", CONFIG_FILE);
 
@@ -71,7 +244,141 @@ impl Config {
         }
     }
 
+    pub fn production_static_directory(&self) -> Utf8PathBuf {
+        if self.rss_r_production_static_directory.as_str().is_empty() {
+            self.rss_r_production_directory.join("static")
+        } else {
+            self.rss_r_production_static_directory.clone()
+        }
+    }
+
+    pub fn upload_directory(&self) -> Utf8PathBuf {
+        if self.upload_directory.as_str().is_empty() {
+            Utf8PathBuf::from(REMOTE_TEMP_DIR)
+        } else {
+            self.upload_directory.clone()
+        }
+    }
+
+    pub fn production_executable(&self) -> Utf8PathBuf {
+        self.rss_r_production_directory.join("rss_r")
+    }
+
+    /// The file `remote_lock` locks.
+    pub fn production_lock_file(&self) -> Utf8PathBuf {
+        self.rss_r_production_directory.join(".deploy.lock")
+    }
+
+    /// The target host, without the port if it has one.
+    pub fn host(&self) -> &str {
+        match split_port(&self.target_host) {
+            Some((host, _)) => host,
+            None => self
+                .target_host
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        }
+    }
+
+    /// The port in `target_host` if it has one, otherwise `target_ip`.
+    pub fn port(&self) -> u32 {
+        self.port_in_target_host()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(self.target_ip)
+    }
+
+    /// The port in `target_host`, like the `2222` in `example.com:2222` or `[::1]:2222`.
+    pub fn port_in_target_host(&self) -> Option<&str> {
+        split_port(&self.target_host).map(|(_, port)| port)
+    }
+
     pub fn host_and_port(&self) -> String {
-        format!("{}:{}", self.target_host, self.target_ip)
+        let host = self.host();
+        if host.contains(':') {
+            // IPv6 addresses need brackets, to tell them apart from the port.
+            format!("[{}]:{}", host, self.port())
+        } else {
+            format!("{}:{}", host, self.port())
+        }
+    }
+}
+
+/// Splits `host:port` or `[address]:port` into the host and the port. `None` if there is no
+/// port. IPv6 addresses without brackets, like `::1`, are never split.
+fn split_port(host: &str) -> Option<(&str, &str)> {
+    if let Some(rest) = host.strip_prefix('[') {
+        let (address, after) = rest.split_once(']')?;
+        return after.strip_prefix(':').map(|port| (address, port));
+    }
+    let (name, port) = host.rsplit_once(':')?;
+    (!name.contains(':')).then_some((name, port))
+}
+
+/// Names of the top level fields in a RON config file.
+fn field_names(contents: &str) -> Option<BTreeSet<String>> {
+    match ron::from_str(contents).ok()? {
+        ron::Value::Map(map) => Some(
+            map.keys()
+                .filter_map(|key| match key {
+                    ron::Value::String(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_host(target_host: &str) -> Config {
+        Config {
+            target_host: target_host.to_string(),
+            target_ip: 22,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_host_with_port() {
+        let config = config_with_host("example.com:2222");
+        assert_eq!(config.host(), "example.com");
+        assert_eq!(config.port(), 2222);
+        assert_eq!(config.host_and_port(), "example.com:2222");
+    }
+
+    #[test]
+    fn test_ipv6_with_port() {
+        let config = config_with_host("[::1]:2222");
+        assert_eq!(config.host(), "::1");
+        assert_eq!(config.port(), 2222);
+        assert_eq!(config.host_and_port(), "[::1]:2222");
+    }
+
+    #[test]
+    fn test_ipv6_in_brackets_without_port() {
+        let config = config_with_host("[::1]");
+        assert_eq!(config.host(), "::1");
+        assert_eq!(config.port_in_target_host(), None);
+        assert_eq!(config.host_and_port(), "[::1]:22");
+    }
+
+    #[test]
+    fn test_bare_ipv6_is_not_split() {
+        let config = config_with_host("::1");
+        assert_eq!(config.host(), "::1");
+        assert_eq!(config.port_in_target_host(), None);
+        assert_eq!(config.host_and_port(), "[::1]:22");
+    }
+
+    #[test]
+    fn test_non_numeric_port_falls_back_to_target_ip() {
+        // `verify_connection_config` refuses it, before connecting.
+        let config = config_with_host("example.com:ssh");
+        assert_eq!(config.host(), "example.com");
+        assert_eq!(config.port_in_target_host(), Some("ssh"));
+        assert_eq!(config.port(), 22);
     }
 }
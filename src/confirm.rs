@@ -0,0 +1,12 @@
+use color_eyre::Result;
+use std::io::{stdin, stdout, Write};
+
+/// Asks a yes/no question on the terminal. Anything other than `y` counts as no.
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    stdout().flush()?;
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
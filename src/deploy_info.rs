@@ -0,0 +1,82 @@
+use crate::archive::sha256_file;
+use crate::config::Config;
+use camino::Utf8Path;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use log::info;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::process::Command;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Name of the marker file that is written to the production directory after a
+/// successful deploy.
+pub const DEPLOY_INFO_FILE: &str = "DEPLOY_INFO";
+
+/// Records what was deployed, so we can later ask a target what is currently live on it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployInfo {
+    /// The configured `deploy_label`, or the git commit the zip was built from.
+    pub label: Option<String>,
+    /// UTC, in RFC 3339 format.
+    pub timestamp: String,
+    /// Local user that ran the deploy.
+    pub deployed_by: String,
+    pub zip_sha256: String,
+}
+
+impl DeployInfo {
+    pub fn for_deploy(config: &Config) -> Result<Self> {
+        let label = if config.deploy_label.is_empty() {
+            detect_git_commit(&config.rss_r_zip)
+        } else {
+            Some(config.deploy_label.clone())
+        };
+
+        let deployed_by = env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_else(|_| config.username.clone());
+
+        Ok(DeployInfo {
+            label,
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339)?,
+            deployed_by,
+            zip_sha256: sha256_file(&config.rss_r_zip)?,
+        })
+    }
+
+    pub fn to_ron(&self) -> Result<String> {
+        to_string_pretty(self, PrettyConfig::default())
+            .context("Could not convert deploy info to RON")
+    }
+
+    pub fn from_ron(contents: &str) -> Result<Self> {
+        ron::from_str(contents).context("Could not parse deploy info")
+    }
+
+    pub fn log(&self) {
+        info!("Label: {}", self.label.as_deref().unwrap_or("(none)"));
+        info!("Deployed at: {}", self.timestamp);
+        info!("Deployed by: {}", self.deployed_by);
+        info!("Zip SHA-256: {}", self.zip_sha256);
+    }
+}
+
+/// The zip usually sits somewhere in the build directory of the rss_r repository,
+/// so if that is a git repo we can tell which commit was built.
+fn detect_git_commit(zip: &Utf8Path) -> Option<String> {
+    let dir = zip.parent().filter(|dir| !dir.as_str().is_empty())?;
+
+    let output = Command::new("git")
+        .args(["-C", dir.as_str(), "rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!commit.is_empty()).then_some(commit)
+}
@@ -0,0 +1,69 @@
+use crate::config::{Config, CONFIG_FILE};
+use log::{info, warn};
+
+/// Logs settings that are valid, but probably not what was meant.
+/// Only advises, it is up to the user whether to change anything.
+pub fn doctor(config: &Config) {
+    let mut findings = 0;
+    let mut warn_about = |message: String| {
+        warn!("{}", message);
+        findings += 1;
+    };
+
+    let test_dir = &config.rss_r_target_test_dir;
+    let production_dir = &config.rss_r_production_directory;
+    if !test_dir.as_str().is_empty() && !production_dir.as_str().is_empty() {
+        if test_dir == production_dir {
+            warn_about(format!(
+                "The test directory and the production directory are both `{}`. A test deploy empties the test directory, so this would remove production",
+                test_dir
+            ));
+        } else if production_dir.starts_with(test_dir) {
+            warn_about(format!(
+                "The production directory `{}` is inside the test directory `{}`. A test deploy empties the test directory, so this would remove production",
+                production_dir, test_dir
+            ));
+        } else if test_dir.starts_with(production_dir) {
+            warn_about(format!(
+                "The test directory `{}` is inside the production directory `{}`",
+                test_dir, production_dir
+            ));
+        }
+    }
+
+    if !config.rss_r_production_user.is_empty() && config.rss_r_production_user == config.username {
+        warn_about(format!(
+            "The production user is the same as the login user `{}`. Changing the owner of the deployed files then does nothing, and the service runs with all rights of that user",
+            config.username
+        ));
+    }
+
+    if let (Some(zip_name), Some(production_name)) = (
+        app_name(config.rss_r_zip.file_stem()),
+        app_name(production_dir.file_name()),
+    ) {
+        if !zip_name.contains(&production_name) && !production_name.contains(&zip_name) {
+            warn_about(format!(
+                "The zip `{}` and the production directory `{}` look like they are for different apps",
+                config.rss_r_zip, production_dir
+            ));
+        }
+    }
+
+    if config.port() != 22 {
+        info!(
+            "The target uses port {}, instead of the usual ssh port 22",
+            config.port()
+        );
+    }
+
+    if findings == 0 {
+        info!("Nothing suspicious found in `{}`", CONFIG_FILE);
+    }
+}
+
+/// Normalizes a file name for comparison, so `rss-r` and `RSS_R` are the same app.
+fn app_name(name: Option<&str>) -> Option<String> {
+    name.filter(|name| !name.is_empty())
+        .map(|name| name.to_lowercase().replace('-', "_"))
+}
@@ -0,0 +1,237 @@
+use crate::archive::{sha256_zip_entries, RSS_R_EXEC_IN_ZIP, STATIC_DIR_IN_ZIP};
+use crate::config::Config;
+use crate::remote::{Remote, RemoteCommand};
+use camino::Utf8Path;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::{info, warn};
+use std::collections::BTreeMap;
+
+/// Compares the deployed executable and static files with the ones in the zip, to find
+/// changes made on the target by hand. Does not change anything on the target.
+/// Fails if there are differences, so it can be used in a periodic check.
+pub fn diff_deployed(config: &Config, remote: &Remote) -> Result<()> {
+    let expected = expected_files(config)?;
+
+    let static_dir = config.production_static_directory();
+    let executable = config.production_executable();
+    // `sha256sum` exits with 1 if the executable does not exist, `find` if the static
+    // directory does not.
+    let output = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!(
+                "sudo sha256sum '{executable}' 2>/dev/null; sudo find '{static_dir}' -type f -exec sha256sum {{}} + 2>/dev/null"
+            ),
+            "Get the SHA-256 of every deployed file, to compare with the zip.",
+        )
+        .allow_exit_codes(&[1]),
+    )?;
+    if remote.is_plan() {
+        return Ok(());
+    }
+
+    // Lines are `<hash>  <path>`.
+    let mut deployed: BTreeMap<String, String> = output
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, path)| (path.to_string(), hash.to_string()))
+        .collect();
+
+    let mut differences = 0;
+    for (path, expected_hash) in &expected {
+        match deployed.remove(path) {
+            None => {
+                warn!("Missing: `{}`", path);
+                differences += 1;
+            }
+            Some(hash) if &hash != expected_hash => {
+                warn!("Changed: `{}`", path);
+                differences += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for path in deployed.keys() {
+        warn!("Extra: `{}`", path);
+        differences += 1;
+    }
+
+    if differences > 0 {
+        return Err(eyre!(
+            "{} file(s) on the target differ from `{}`",
+            differences,
+            config.rss_r_zip
+        ));
+    }
+    info!(
+        "The {} deployed files match `{}`",
+        expected.len(),
+        config.rss_r_zip
+    );
+    Ok(())
+}
+
+/// The SHA-256 of every file a production deploy puts on the target, by where it is put.
+fn expected_files(config: &Config) -> Result<BTreeMap<String, String>> {
+    let static_dir = config.production_static_directory();
+
+    let mut expected = BTreeMap::new();
+    for (name, hash) in sha256_zip_entries(&config.rss_r_zip)? {
+        if name == RSS_R_EXEC_IN_ZIP {
+            expected.insert(config.production_executable().to_string(), hash);
+            continue;
+        }
+        let Some(static_file) = name.strip_prefix(STATIC_DIR_IN_ZIP) else {
+            continue;
+        };
+        if config
+            .static_exclude_globs
+            .iter()
+            .any(|glob| glob_matches(glob, static_file))
+        {
+            continue;
+        }
+        // Static files are extracted without their directories, see `deploy_production`.
+        let file_name = Utf8Path::new(static_file)
+            .file_name()
+            .unwrap_or(static_file);
+        expected.insert(static_dir.join(file_name).to_string(), hash);
+    }
+
+    Ok(expected)
+}
+
+/// Matches like the `unzip` patterns of `static_exclude_globs`: `*` matches anything,
+/// including `/`, `?` matches a single character, and `[...]` one of the characters between
+/// the brackets. Ranges like `[a-z]` are allowed, and `[!...]` or `[^...]` match any character
+/// that is not between them.
+fn glob_matches(glob: &str, text: &str) -> bool {
+    match glob.chars().next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &glob[1..];
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| glob_matches(rest, &text[i..]))
+        }
+        Some('[') => {
+            let Some((set, rest)) = split_set(&glob[1..]) else {
+                return false;
+            };
+            let mut chars = text.chars();
+            match chars.next() {
+                Some(t) if set_contains(set, t) => glob_matches(rest, chars.as_str()),
+                _ => false,
+            }
+        }
+        Some(c) => {
+            let mut chars = text.chars();
+            match chars.next() {
+                Some(t) if c == '?' || c == t => {
+                    glob_matches(&glob[c.len_utf8()..], chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Whether `glob` only uses the pattern syntax that [glob_matches] handles the same as `unzip`.
+/// So no `\` escapes, no `[` without a `]`, and no `-` at the end of a set, like `[a-]`.
+/// `unzip` does not match the `-` then.
+pub fn is_supported_glob(glob: &str) -> bool {
+    if glob.contains('\\') {
+        return false;
+    }
+    let mut rest = glob;
+    while let Some(i) = rest.find('[') {
+        let Some((set, after)) = split_set(&rest[i + 1..]) else {
+            return false;
+        };
+        let set = set.strip_prefix(['!', '^']).unwrap_or(set);
+        if set.len() > 1 && set.ends_with('-') {
+            return false;
+        }
+        rest = after;
+    }
+    true
+}
+
+/// Splits the glob after a `[` into what is between the brackets, and the rest of the glob.
+/// `None` if the set is empty, or not closed.
+fn split_set(glob: &str) -> Option<(&str, &str)> {
+    let start = usize::from(glob.starts_with(['!', '^']));
+    let end = start + glob[start..].find(']')?;
+    if end == start {
+        return None;
+    }
+    Some((&glob[..end], &glob[end + 1..]))
+}
+
+fn set_contains(set: &str, c: char) -> bool {
+    let (negated, set) = match set.strip_prefix(['!', '^']) {
+        Some(set) => (true, set),
+        None => (false, set),
+    };
+    let chars: Vec<char> = set.chars().collect();
+    let mut found = false;
+    let mut i = 0;
+    while i < chars.len() {
+        if i + 2 < chars.len() && chars[i + 1] == '-' {
+            found |= (chars[i]..=chars[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= chars[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_star_matches_across_directories() {
+        assert!(glob_matches("*.map", "main.js.map"));
+        assert!(glob_matches("*.map", "assets/main.js.map"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("*.map", "main.js"));
+    }
+
+    #[test]
+    fn test_question_mark_matches_one_character() {
+        assert!(glob_matches("?.js", "a.js"));
+        assert!(glob_matches("?.js", "é.js"));
+        assert!(!glob_matches("?.js", ".js"));
+        assert!(!glob_matches("?.js", "ab.js"));
+    }
+
+    #[test]
+    fn test_character_sets() {
+        assert!(glob_matches("[ab].js", "a.js"));
+        assert!(!glob_matches("[ab].js", "c.js"));
+        assert!(glob_matches("v[0-9].css", "v7.css"));
+        assert!(!glob_matches("v[0-9].css", "vx.css"));
+        assert!(glob_matches("[!a].js", "b.js"));
+        assert!(!glob_matches("[!a].js", "a.js"));
+        assert!(glob_matches("[^a-c]*", "d.txt"));
+        assert!(!glob_matches("[^a-c]*", "b.txt"));
+        assert!(glob_matches("[-a]", "-"));
+        assert!(glob_matches("[!-]", "a"));
+    }
+
+    #[test]
+    fn test_unsupported_globs() {
+        assert!(is_supported_glob("*.map"));
+        assert!(is_supported_glob("[!a-z]?.js"));
+        assert!(!is_supported_glob("\\*.map"));
+        assert!(!is_supported_glob("[a-z"));
+        assert!(!is_supported_glob("[].js"));
+        assert!(!is_supported_glob("[!].js"));
+        assert!(!is_supported_glob("[a-].js"));
+        assert!(is_supported_glob("[-].js"));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::Config;
+use crate::remote::{Remote, RemoteCommand};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::info;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// `curl` exit codes for when there was no HTTP response at all:
+/// could not connect, timed out, empty reply, and connection reset.
+const CURL_NO_RESPONSE_CODES: [i32; 4] = [7, 28, 52, 56];
+/// How long a single attempt may take, so a hanging service doesn't stall the deploy.
+const ATTEMPT_TIMEOUT_SECS: u32 = 5;
+
+/// Polls the `health_check_url` from the target, until it answers with a 2xx status.
+/// Returns an error if it didn't within `health_check_retries` attempts.
+pub fn wait_until_healthy(config: &Config, remote: &Remote) -> Result<()> {
+    let url = &config.health_check_url;
+    if url.is_empty() {
+        return Ok(());
+    }
+
+    let command = RemoteCommand::new(
+        format!(
+            "curl -s -o /dev/null -w '%{{http_code}}' --max-time {ATTEMPT_TIMEOUT_SECS} '{url}'"
+        ),
+        "Check that the new version answers requests.",
+    )
+    .allow_exit_codes(&CURL_NO_RESPONSE_CODES);
+
+    if remote.is_plan() {
+        remote.execute_command_capture(command)?;
+        return Ok(());
+    }
+
+    let attempts = config.health_check_retries.max(1);
+    // The last HTTP status we got, if any. Stays set when a later attempt gets no response,
+    // because a service that answered before is clearly listening.
+    let mut last_status = None;
+    for attempt in 1..=attempts {
+        // `curl` reports `000` when there was no response.
+        let output = remote.execute_command_capture(command.clone())?;
+        let status = output.trim().parse::<u16>().ok().filter(|&code| code != 0);
+
+        match status {
+            Some(code) => info!("Health check attempt {}/{}: {}", attempt, attempts, code),
+            None => info!(
+                "Health check attempt {}/{}: not listening yet",
+                attempt, attempts
+            ),
+        }
+        if status.is_some_and(|code| (200..300).contains(&code)) {
+            info!("Health check passed");
+            return Ok(());
+        }
+        last_status = status.or(last_status);
+
+        if attempt < attempts {
+            sleep(Duration::from_secs(config.health_check_interval_secs));
+        }
+    }
+
+    Err(match last_status {
+        Some(code) => eyre!(
+            "Health check of `{}` failed: it kept answering with status {}. The service is running, but probably failed to start up properly",
+            url,
+            code
+        ),
+        None => eyre!(
+            "Health check of `{}` failed: nothing was listening after {} attempts. If the service needs longer to start, increase `health_check_retries` or `health_check_interval_secs`",
+            url,
+            attempts
+        ),
+    })
+}
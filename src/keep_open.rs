@@ -0,0 +1,88 @@
+use crate::config::Config;
+use crate::remote::Remote;
+use crate::shell::interactive_shell;
+use crate::Args;
+use color_eyre::{Report, Result};
+use log::{error, info, warn};
+use std::io::stdin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to check for Ctrl+c while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set once the failure was paused for, so a failure is only paused for once.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// After a failure, lets the failed state be looked at before it is rolled back, or the
+/// connection is closed. With `--on-failure-keep-session-open` and `--interactive`.
+/// Only done the first time it is called.
+pub fn pause_on_failure(config: &Config, args: &Args, remote: &Remote, error: &Report) {
+    let pause = args.on_failure_keep_session_open.is_some() || args.interactive;
+    if !pause || remote.is_plan() || PAUSED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    error!("{:#}", error);
+    if let Some(seconds) = args.on_failure_keep_session_open {
+        keep_session_open(config, remote, Duration::from_secs(seconds));
+    }
+    if args.interactive {
+        info!("Opening a shell on the target. Exit it to continue.");
+        if let Err(e) = interactive_shell(remote) {
+            warn!("Could not open a shell on the target: {:#}", e);
+        }
+    }
+}
+
+/// Pauses if `result` is a failure, see [pause_on_failure]. For where uploads and the remote
+/// lock are still held, because they are cleaned up when they are dropped.
+pub fn pause_if_failed<T>(
+    config: &Config,
+    args: &Args,
+    remote: &Remote,
+    result: Result<T>,
+) -> Result<T> {
+    if let Err(e) = &result {
+        pause_on_failure(config, args, remote, e);
+    }
+    result
+}
+
+/// Shows how to log in on the target, and waits with disconnecting until Enter or Ctrl+c is
+/// pressed, or `timeout` has passed. So the failed state can be looked at before the machine
+/// running the deploy goes away.
+fn keep_session_open(config: &Config, remote: &Remote, timeout: Duration) {
+    info!("The deploy failed. To look around on the target:");
+    info!(
+        "  ssh -p {} {}@{}",
+        config.port(),
+        config.username,
+        config.host()
+    );
+    info!(
+        "Press Enter to continue, or wait {} seconds",
+        timeout.as_secs()
+    );
+
+    // Stdin can only be read blocking, so it is done in a separate thread. Which stays
+    // behind when the timeout passes, but we are about to exit anyway.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Ok(1..) = stdin().read_line(&mut String::new()) {
+            let _ = sender.send(());
+        }
+    });
+
+    let start = Instant::now();
+    while start.elapsed() < timeout && !remote.take_interrupt() {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(()) => return,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            // Stdin is closed, like in CI. Then only the timeout counts.
+            Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::config::Config;
+use crate::confirm::confirm;
+use crate::remote::{Remote, RemoteCommand};
+use crate::{sudo_chmod_created_dir, unique_name, StagedUpload};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::info;
+use time::format_description;
+use time::OffsetDateTime;
+
+/// `diff` exits with 1 when the files differ.
+const DIFF_FILES_DIFFER: i32 = 1;
+
+/// Where rss_r keeps its state and config in production.
+pub fn persistence_directory(config: &Config) -> Utf8PathBuf {
+    config.rss_r_production_directory.join("persistence")
+}
+
+/// Where rss_r reads its config from, relative to its working directory.
+pub fn live_config_path(config: &Config) -> Utf8PathBuf {
+    persistence_directory(config).join("app_config.ron")
+}
+
+/// Uploads the configured production app config to the temp directory, and shows how it differs
+/// from the live one. Asks to confirm the change, unless `skip_confirmation`.
+/// Returns the uploaded file, or `None` if the config is not changed. The config can have
+/// secrets in it, so the uploaded file is removed when it is dropped, also if it is not used.
+///
+/// Done before the service is stopped, so saying no doesn't leave production down.
+pub fn stage_production_config<'a>(
+    config: &Config,
+    remote: &'a Remote,
+    skip_confirmation: bool,
+) -> Result<Option<StagedUpload<'a>>> {
+    if config.rss_r_production_config_file.as_str().is_empty() {
+        return Ok(None);
+    }
+
+    // Under a name of its own, so it is not mixed up with an earlier or concurrent deploy.
+    let staged = StagedUpload::with_unique_path(
+        remote,
+        config
+            .upload_directory()
+            .join(unique_name("app_config.ron")?),
+    );
+    let staged_path = staged.path();
+    remote.upload_private_file(
+        &config.rss_r_production_config_file,
+        staged_path,
+        "Upload the new production app config, to compare it with the live one.",
+    )?;
+
+    let live_path = live_config_path(config);
+    // `/dev/null` when there is no live config yet, so the diff shows the whole new file.
+    let diff = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!(
+                "if sudo test -e '{live_path}'; then sudo diff -u '{live_path}' '{staged_path}'; else diff -u /dev/null '{staged_path}'; fi"
+            ),
+            "Show what changes in the live app config.",
+        )
+        .allow_exit_codes(&[DIFF_FILES_DIFFER]),
+    )?;
+    if remote.is_plan() {
+        return Ok(Some(staged));
+    }
+
+    if diff.trim().is_empty() {
+        info!("The production app config did not change");
+        return Ok(None);
+    }
+
+    info!("Changes to the production app config `{}`:", live_path);
+    println!("{}", diff.trim_end());
+    if !skip_confirmation && !confirm("Overwrite the production app config?")? {
+        return Err(eyre!(
+            "Deploy cancelled, the production app config was not changed"
+        ));
+    }
+
+    Ok(Some(staged))
+}
+
+/// Backs up the live app config to a timestamped file, and replaces it with the staged one.
+/// Returns the backup, or `None` if there was no live config yet.
+pub fn install_production_config(
+    config: &Config,
+    remote: &Remote,
+    staged_path: &Utf8Path,
+) -> Result<Option<Utf8PathBuf>> {
+    let live_path = live_config_path(config);
+    let timestamp = OffsetDateTime::now_utc().format(&format_description::parse(
+        "[year][month][day]T[hour][minute][second]Z",
+    )?)?;
+    let backup_path = Utf8PathBuf::from(format!("{live_path}.{timestamp}"));
+
+    // `test` exits with 1 if there is no live config yet.
+    let live_exists = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!("sudo test -e '{live_path}' && echo exists"),
+            "Check whether there is a live app config to back up.",
+        )
+        .allow_exit_codes(&[1]),
+    )?;
+    let backup = if remote.is_plan() || !live_exists.trim().is_empty() {
+        info!("Backing up the production app config to `{}`", backup_path);
+        remote.execute_command(RemoteCommand::new(
+            format!("sudo cp -p '{live_path}' '{backup_path}'"),
+            "Keep the live app config, in case the new one does not work.",
+        ))?;
+        Some(backup_path)
+    } else {
+        None
+    };
+
+    let user = &config.rss_r_production_user;
+    let live_dir = persistence_directory(config);
+    // On a first deploy, the service needs to be able to write its state there.
+    let chmod = sudo_chmod_created_dir(config, &live_dir);
+    remote.execute_command(RemoteCommand::new(
+        format!(
+            "sudo test -d '{live_dir}' || {{ sudo mkdir -p '{live_dir}' && sudo chown '{user}':'{user}' '{live_dir}'{chmod}; }}"
+        ),
+        "Create the directory for the app state and config, if this is the first deploy.",
+    ))?;
+
+    info!("Installing the new production app config");
+    remote.execute_command(
+        RemoteCommand::new(
+            format!(
+                "sudo mv '{staged_path}' '{live_path}' && sudo chown '{user}':'{user}' '{live_path}'"
+            ),
+            "Replace the live app config, and give it to the user the service runs as.",
+        )
+        .destructive(),
+    )?;
+
+    Ok(backup)
+}
@@ -1,19 +1,62 @@
+mod additional_archives;
+mod archive;
+mod changelog;
 mod config;
-
+mod confirm;
+mod deploy_info;
+mod doctor;
+mod drift;
+mod health;
+mod keep_open;
+mod live_config;
+mod permissions;
+mod plan;
+mod port_forward;
+mod preflight;
+mod production;
+mod profile;
+mod remote;
+mod report;
+mod service;
+mod shell;
+mod signature;
+mod watch;
+
+use crate::archive::{log_zip_differences, sha256_file};
+use crate::changelog::confirm_changes_since_last_deploy;
 use crate::config::{Config, CONFIG_FILE};
+use crate::doctor::doctor;
+use crate::drift::{diff_deployed, is_supported_glob};
+use crate::keep_open::{pause_if_failed, pause_on_failure};
+use crate::permissions::PermissionCheck;
+use crate::plan::{print_plan, print_plan_json, write_script};
+use crate::port_forward::{forward_local_port, PortForward};
+use crate::preflight::check_container_running;
+use crate::production::{deploy_files, deploy_production, show_deployed};
+use crate::profile::print_profile;
+use crate::remote::{shell_quote, Remote, RemoteCommand};
+use crate::report::write_report;
+use crate::service::{start_service, stop_service};
+use crate::signature::verify_archive_signature;
+use crate::watch::watch_file;
 use camino::{Utf8Path, Utf8PathBuf};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use color_eyre::config::{HookBuilder, Theme};
 use color_eyre::eyre::{eyre, OptionExt};
 use color_eyre::{eyre::WrapErr, Result};
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use signal_hook::consts::SIGINT;
-use signal_hook::iterator::Signals;
+use signal_hook::flag;
 use simplelog::{format_description, ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
-use ssh2::{ExtendedData, Session};
-use std::fs::File;
-use std::io::{stdout, Read, Write};
-use std::net::TcpStream;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
 use std::process::exit;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
 
 const REMOTE_TEMP_DIR: &str = "/tmp";
 
@@ -22,20 +65,213 @@ const REMOTE_TEMP_DIR: &str = "/tmp";
 struct Args {
     #[arg(short, long)]
     production: bool,
+    /// Only deploy these files from the zip to production, for hotfixes. Comma separated paths
+    /// as in the zip, like `rss_r/static/index.html`. The static directory is not emptied.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "PATHS",
+        requires = "production"
+    )]
+    files: Vec<String>,
+    /// Create the production directory if it does not exist yet, for the first deploy to a new
+    /// target.
+    #[arg(long, requires = "production")]
+    create_dirs: bool,
+    /// Before a production deploy, show what is deployed now and what is about to be, with the
+    /// git log in between if available. Then ask whether to continue.
+    #[arg(long, requires = "production")]
+    since_last_deploy: bool,
+    /// After a production deploy, check that the permissions of the deployed files match the
+    /// policy in the config. `fail` (the default) rolls the deploy back when they don't, `warn`
+    /// only logs the files.
+    #[arg(
+        long,
+        value_name = "ON_MISMATCH",
+        num_args = 0..=1,
+        default_missing_value = "fail",
+        requires = "production"
+    )]
+    verify_permissions: Option<PermissionCheck>,
+    /// After a production deploy, check that the production user can read and execute the
+    /// deployed files, by trying as that user. The deploy is rolled back if it can't.
+    #[arg(long, requires = "production")]
+    verify_access: bool,
+    /// Don't ask for confirmation.
+    #[arg(short, long)]
+    yes: bool,
+    /// Never write to the config file, not even to add newly introduced fields.
+    /// For people who manage the config file by hand.
+    #[arg(long)]
+    no_config_save: bool,
+    /// Show what is currently deployed to production, according to the `DEPLOY_INFO` file.
+    #[arg(long)]
+    show_deployed: bool,
+    /// Compare the deployed executable and static files with the ones in `rss_r_zip`, and
+    /// report files that are missing, extra or changed. Does not deploy anything.
+    #[arg(long)]
+    diff_only: bool,
+    /// Compare the configured `rss_r_zip` against a previous zip, given with `--against`.
+    /// Does not connect to the target.
+    #[arg(long, requires = "against")]
+    diff_versions: bool,
+    #[arg(long, value_name = "PATH")]
+    against: Option<Utf8PathBuf>,
+    /// Don't deploy, but list the commands and uploads a deploy would do.
+    #[arg(long, visible_alias = "dry-run")]
+    show_plan: bool,
+    /// Like `--show-plan`, but also explain why each step is done.
+    #[arg(long)]
+    explain: bool,
+    /// Don't deploy, but write the commands and uploads a deploy would do to a bash script,
+    /// which does them over `ssh` and `scp`.
+    #[arg(long, value_name = "PATH")]
+    emit_script: Option<Utf8PathBuf>,
+    /// Don't deploy, but print the commands and uploads a deploy would do as JSON.
+    /// The log goes to stderr, so stdout only contains the JSON.
+    #[arg(long, conflicts_with_all = ["show_plan", "explain"])]
+    plan_json: bool,
+    /// Run the test deploy again every time the zip changes.
+    #[arg(long, conflicts_with_all = ["production", "show_plan", "explain", "emit_script", "plan_json"])]
+    watch: bool,
+    /// After a test deploy, run the test instance on the target.
+    /// With `--watch`, it runs in the background, and is stopped before each redeploy.
+    #[arg(short, long, conflicts_with = "production")]
+    run: bool,
+    /// Environment variable to run the test instance with, as `KEY=VALUE`. Can be given multiple
+    /// times. Overrides the same variable in `test_run_env`.
+    #[arg(long, value_name = "KEY=VALUE", requires = "run")]
+    env: Vec<String>,
+    /// Stop a test instance that is still running from an earlier `--run`, instead of refusing
+    /// to start a new one.
+    #[arg(long, requires = "run")]
+    kill_existing: bool,
+    /// Let the test deploy empty the test directory, also when it has app state in a
+    /// `persistence` directory. Without this, the test deploy refuses to.
+    #[arg(long, conflicts_with = "production")]
+    wipe_persistence: bool,
+    /// Upload the zip over SFTP, continuing a previously interrupted upload if there is one.
+    /// Only use this if the partial file on the target came from the same zip.
+    #[arg(long)]
+    resume_upload: bool,
+    /// Upload the zip, and the other files that are staged in the upload directory, under unique
+    /// names with a timestamp and a random suffix. So concurrent deploys to the same target
+    /// don't overwrite each other's uploads. The uploads are removed once they are used, or
+    /// when the deploy fails.
+    #[arg(long, conflicts_with = "resume_upload")]
+    include_timestamp_in_zip_name: bool,
+    /// Don't color the output. Also done when the `NO_COLOR` environment variable is set.
+    #[arg(long)]
+    no_color: bool,
+    /// How much to log: `error`, `warn`, `info`, `debug` or `trace`.
+    /// With `debug`, the exit code and duration of every remote command is logged.
+    #[arg(long, default_value = "info")]
+    log_level: LevelFilter,
+    /// When the deploy fails, open a shell on the target over the same connection, to look
+    /// around. A failed production deploy is rolled back, and the uploads and the remote lock
+    /// are cleaned up, after the shell exits.
+    #[arg(long)]
+    interactive: bool,
+    /// When the deploy fails, show how to log in on the target, and wait with disconnecting
+    /// (and with rolling back and cleaning up) until Enter is pressed, or this many seconds
+    /// have passed.
+    /// For looking around on the target, when the deploy runs on a short-lived CI runner.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "600",
+        conflicts_with_all = ["watch", "show_plan", "explain", "emit_script", "plan_json"]
+    )]
+    on_failure_keep_session_open: Option<u64>,
+    /// After the deploy, show where the time went. Like how much of it was spent on round-trips
+    /// to the target.
+    #[arg(long)]
+    profile: bool,
+    /// Write a report of the deploy to this file, as JSON. With the time, deployer, target, zip,
+    /// every command with its exit code and duration, and whether the deploy succeeded.
+    /// Also written when the deploy fails.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["watch", "show_plan", "explain", "emit_script", "plan_json"])]
+    report: Option<Utf8PathBuf>,
+    /// After the deploy, forward a local port to a port on the target, to try out the deployed
+    /// instance in a browser without exposing it. `PORT` to use the same port on both sides,
+    /// or `LOCAL_PORT:REMOTE_PORT`. Forwards until Ctrl+c is pressed.
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["watch", "show_plan", "explain", "emit_script", "plan_json"])]
+    local_port_forward: Option<String>,
+    /// Stop forwarding the port after this many seconds.
+    #[arg(long, value_name = "SECONDS", requires = "local_port_forward")]
+    forward_secs: Option<u64>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a single command on the target, using the connection settings from the config.
+    /// Exits with the exit code of the command.
+    Exec {
+        /// For example: `exec -- systemctl status rss_r`.
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Work with the config file.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Stop or start the production service, and show its status.
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+}
 
-    configure_logging()?;
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Warn about settings that are valid, but probably a mistake.
+    /// Like the test and production directory being the same.
+    Doctor,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Stop the service, for example for maintenance.
+    Stop,
+    /// Start the service, for example after manual work on the target.
+    Start,
+}
 
+fn main() -> Result<()> {
+    let start = Instant::now();
+    let started_at = OffsetDateTime::now_utc();
     let args = Args::parse();
 
+    // See https://no-color.org
+    let color = !args.no_color && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+
+    if color {
+        color_eyre::install()?;
+    } else {
+        HookBuilder::default().theme(Theme::new()).install()?;
+    }
+
+    configure_logging(
+        args.log_level,
+        color,
+        if args.plan_json {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Mixed
+        },
+    )?;
+
     let config = match Config::load() {
         Some(config) => {
             // Save again, in case this script has additional parameters that were not yet listed
             // in the config file.
-            config.save();
+            if !args.no_config_save {
+                config.save_if_fields_missing();
+            }
             config
         }
         None => {
@@ -48,26 +284,180 @@ fn main() -> Result<()> {
         }
     };
 
+    if let (true, Some(against)) = (args.diff_versions, &args.against) {
+        // Purely local, so the rest of the config does not need to be valid.
+        return log_zip_differences(against, &config.rss_r_zip);
+    }
+
+    if let Some(Command::Config {
+        command: ConfigCommand::Doctor,
+    }) = &args.command
+    {
+        doctor(&config);
+        return Ok(());
+    }
+
+    if let Some(Command::Service { command }) = &args.command {
+        if !verify_connection_config(&config) {
+            exit(1);
+        }
+        let remote = Remote::connect(&config, listen_for_interrupt())?;
+        if config.sudo_requires_password {
+            remote.prime_sudo(&config.username)?;
+        }
+        return match command {
+            ServiceCommand::Stop => stop_service(&config, &remote),
+            ServiceCommand::Start => start_service(&config, &remote),
+        };
+    }
+
+    if let Some(Command::Exec { command }) = &args.command {
+        if !verify_connection_config(&config) {
+            exit(1);
+        }
+        let remote = Remote::connect(&config, listen_for_interrupt())?;
+        let exit_code = remote.execute_command_status(&RemoteCommand::new(
+            command.join(" "),
+            "Run the command given on the command line.",
+        ))?;
+        exit(exit_code);
+    }
+
     if !verify_config(&config) {
         exit(1);
     }
 
-    if args.production {
-        deploy_production(&config)?;
-    } else {
-        deploy_to_test_dir(&config)?;
+    let interrupted = listen_for_interrupt();
+
+    if args.show_deployed {
+        let remote = Remote::connect(&config, interrupted)?;
+        return show_deployed(&config, &remote);
+    }
+
+    if args.diff_only {
+        let remote = Remote::connect(&config, interrupted)?;
+        return diff_deployed(&config, &remote);
+    }
+
+    if args.watch {
+        // After the first redeploy, the running test instance is our own.
+        let mut started = false;
+        return watch_file(&config.rss_r_zip, interrupted.as_ref(), || {
+            verify_archive_signature(&config)?;
+            let remote = Remote::connect(&config, interrupted.clone())?;
+            if args.run {
+                // Before the test directory is emptied, and so the new one can use the port.
+                let kill = args.kill_existing || started;
+                check_for_running_test_instance(&remote, &test_executable(&config), kill)?;
+            }
+            deploy_to_test_dir(&config, &args, &remote)?;
+            if args.run {
+                run_test_rss_r_in_background(&config, &args, &remote)?;
+                started = true;
+            }
+            Ok(())
+        });
+    }
+
+    let port_forward = args
+        .local_port_forward
+        .as_deref()
+        .map(PortForward::parse)
+        .transpose()?;
+
+    let plan_only = args.show_plan || args.explain || args.emit_script.is_some() || args.plan_json;
+    // Everything that can fail the deploy is done here, so it ends up in the report.
+    let mut connected = None;
+    let result = verify_archive_signature(&config).and_then(|()| {
+        let remote = if plan_only {
+            Remote::plan()
+        } else {
+            Remote::connect(&config, interrupted)?
+        };
+        deploy(&config, &args, connected.insert(remote))
+    });
+    if let Some(report_path) = &args.report {
+        // Not failing on it, that would hide how the deploy went.
+        match write_report(
+            report_path,
+            &config,
+            connected.as_ref(),
+            args.production,
+            started_at,
+            &result,
+        ) {
+            Ok(()) => info!("Wrote the deploy report to `{}`", report_path),
+            Err(e) => warn!(
+                "Could not write the deploy report `{}`: {:#}",
+                report_path, e
+            ),
+        }
+    }
+    let remote = match (result, connected) {
+        (Ok(()), Some(remote)) => remote,
+        (Err(e), connected) => {
+            if let Some(remote) = &connected {
+                pause_on_failure(&config, &args, remote, &e);
+            }
+            return Err(e);
+        }
+        (Ok(()), None) => unreachable!("the deploy succeeded without connecting"),
+    };
+
+    if let Some(script_path) = &args.emit_script {
+        write_script(&remote.planned_steps(), &config, script_path)?;
+        info!("Wrote the deploy steps to `{}`", script_path);
+    }
+    if args.show_plan || args.explain {
+        print_plan(&remote.planned_steps(), args.explain);
+    }
+    if args.plan_json {
+        print_plan_json(&remote.planned_steps(), &config)?;
+    }
+    if args.profile && !remote.is_plan() {
+        let total = start.elapsed();
+        print_profile(&remote.stats(), total, remote.measure_round_trip()?);
+    }
+    if let Some(port_forward) = port_forward {
+        forward_local_port(
+            &remote,
+            port_forward,
+            args.forward_secs.map(Duration::from_secs),
+        )?;
     }
 
     Ok(())
 }
 
+fn deploy(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    if args.since_last_deploy {
+        confirm_changes_since_last_deploy(config, remote, args.yes)?;
+    }
+
+    if args.production && !args.files.is_empty() {
+        deploy_files(config, args, remote, &args.files)
+    } else if args.production {
+        deploy_production(config, args, remote)
+    } else {
+        deploy_to_test_dir(config, args, remote)?;
+
+        if args.run {
+            run_test_rss_r(config, args, remote)?;
+        }
+        Ok(())
+    }
+}
+
 fn verify_config(config: &Config) -> bool {
-    if config.target_host.is_empty() {
-        error!("Please configure a target host.");
+    if !verify_connection_config(config) {
         return false;
     }
-    if config.username.is_empty() {
-        error!("Please configure a username.");
+    // Uploads land on the target itself, and its `/tmp` is not visible in the container.
+    if !config.remote_container.is_empty() && config.upload_directory.as_str().is_empty() {
+        error!(
+            "Please configure an upload directory that is bind-mounted into container `{}`.",
+            config.remote_container
+        );
         return false;
     }
     if !config.rss_r_zip.exists() {
@@ -85,6 +475,11 @@ fn verify_config(config: &Config) -> bool {
         );
         return false;
     }
+    // A mistake in the app config would otherwise only show up when the test instance
+    // fails to start.
+    if !verify_ron_file(&config.rss_r_test_config_file, "test config file") {
+        return false;
+    }
 
     if config.rss_r_production_directory.to_string().is_empty() {
         error!("Please configure a target directory for production.");
@@ -94,249 +489,551 @@ fn verify_config(config: &Config) -> bool {
         error!("Please configure a production user.");
         return false;
     }
+    let production_config_file = &config.rss_r_production_config_file;
+    if !production_config_file.as_str().is_empty() {
+        if !production_config_file.exists() {
+            error!(
+                "production config file does not exist: `{}`",
+                production_config_file
+            );
+            return false;
+        }
+        if !verify_ron_file(production_config_file, "production config file") {
+            return false;
+        }
+    }
+    if config.service_name.is_empty() {
+        error!("Please configure the name of the production service.");
+        return false;
+    }
+    if !config.deploy_unit_file.as_str().is_empty() && !config.deploy_unit_file.exists() {
+        error!("unit file does not exist: `{}`", config.deploy_unit_file);
+        return false;
+    }
+    for archive in &config.additional_archives {
+        if !archive.zip.exists() {
+            error!("additional archive does not exist: `{}`", archive.zip);
+            return false;
+        }
+        if !archive.target_directory.is_absolute() {
+            error!(
+                "The target directory of additional archive `{}` should be an absolute path: `{}`",
+                archive.zip, archive.target_directory
+            );
+            return false;
+        }
+    }
+    let dir_mode = &config.created_dir_mode;
+    let valid_dir_mode =
+        (3..=4).contains(&dir_mode.len()) && dir_mode.chars().all(|c| ('0'..='7').contains(&c));
+    if !dir_mode.is_empty() && !valid_dir_mode {
+        error!(
+            "The created directory mode should be octal, like `700`: `{}`",
+            dir_mode
+        );
+        return false;
+    }
+    // `--diff-only` has to leave out the same files as `unzip` does.
+    if let Some(glob) = config
+        .static_exclude_globs
+        .iter()
+        .find(|glob| !is_supported_glob(glob))
+    {
+        error!(
+            "Static exclude glob `{}` is not supported. Use `*`, `?` and `[...]` without `\\` escapes, and don't end a `[...]` with `-`.",
+            glob
+        );
+        return false;
+    }
+    let static_dir = &config.rss_r_production_static_directory;
+    if !static_dir.as_str().is_empty() {
+        if !static_dir.is_absolute() {
+            error!(
+                "The production static directory should be an absolute path: `{}`",
+                static_dir
+            );
+            return false;
+        }
+        // The static directory gets emptied on deploy, which should not take the executable
+        // with it.
+        if config.rss_r_production_directory.starts_with(static_dir) {
+            error!(
+                "The production static directory `{}` should not contain the production directory `{}`",
+                static_dir, config.rss_r_production_directory
+            );
+            return false;
+        }
+    }
 
     true
 }
 
-fn deploy_production(config: &Config) -> Result<()> {
-    let session = connect_and_login(config)?;
-
-    info!("Stopping rss_r service");
-    execute_command(&session, "sudo systemctl stop rss_r")?;
-
-    let remote_zip_path = upload_zip_to_tmp_dir(config, &session)?;
-
-    info!("Check if zip contains expected files");
-    let rss_r_exec_in_zip = Utf8PathBuf::from("rss_r/rss_r");
-    let static_dir_in_zip = Utf8PathBuf::from("rss_r/static/");
+fn verify_ron_file(path: &Utf8Path, description: &str) -> bool {
+    let parses = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            ron::from_str::<ron::Value>(&contents)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = parses {
+        error!("{} is not valid RON: `{}`: {}", description, path, e);
+        return false;
+    }
 
-    execute_command(
-        &session,
-        &format!(
-            "unzip -l '{}' | grep -q '{}'",
-            remote_zip_path, rss_r_exec_in_zip
-        ),
-    )
-    .with_context(|| format!("Zip does not contain `{}`", rss_r_exec_in_zip))?;
-    execute_command(
-        &session,
-        &format!(
-            "unzip -l '{}' | grep -q '{}'",
-            remote_zip_path, static_dir_in_zip
-        ),
-    )
-    .with_context(|| format!("Zip does not contain `{}`", static_dir_in_zip))?;
-    info!("Expected files found");
-
-    // The old static directory needs removing to make sure there are no old files
-    // left behind. Because the `unzip` command will only add or overwrite files.
-    info!("Removing old static directory");
-    let mut target_static_dir = config.rss_r_production_directory.clone();
-    target_static_dir.push("static");
-    // TODO (2024-09-08): Make this command not fail if the static dir is not there.
-    execute_command(&session, &format!("sudo rm -r '{target_static_dir}'"))?;
-
-    info!("Extracting rss_r exe and static directory");
-    // `-j`: unzip only the files specified, do not create their parent directories.
-    // `-o`: Overwrite files without prompting.
-    execute_command(
-        &session,
-        &format!(
-            "sudo unzip -j -o '{remote_zip_path}' '{rss_r_exec_in_zip}' -d {}",
-            config.rss_r_production_directory
-        ),
-    )?;
-    execute_command(
-        &session,
-        &format!(
-            "sudo unzip -j -o '{remote_zip_path}' '{static_dir_in_zip}*' -d {target_static_dir}",
-        ),
-    )?;
+    true
+}
 
-    info!("Setting ownership to {}", config.rss_r_production_user);
-    let mut target_rss_exe = config.rss_r_production_directory.clone();
-    target_rss_exe.push("rss_r");
-    execute_command(
-        &session,
-        &format!(
-            "sudo chown '{}':'{}' '{}'",
-            config.rss_r_production_user, config.rss_r_production_user, target_rss_exe
-        ),
-    )?;
-    execute_command(
-        &session,
-        &format!(
-            "sudo chown -R '{}':'{}' '{}'",
-            config.rss_r_production_user, config.rss_r_production_user, target_static_dir
-        ),
-    )?;
+/// Only the parts of the config needed to log in on the target.
+fn verify_connection_config(config: &Config) -> bool {
+    if config.target_host.is_empty() {
+        error!("Please configure a target host.");
+        return false;
+    }
+    if config.username.is_empty() {
+        error!("Please configure a username.");
+        return false;
+    }
+    if let Some(port) = config.port_in_target_host() {
+        if port.parse::<u16>().is_err() {
+            error!(
+                "`{}` is not a valid port, in target host `{}`. Please put the port in `target_ip` instead.",
+                port, config.target_host
+            );
+            return false;
+        }
+        if config.port() != config.target_ip {
+            warn!(
+                "The target host `{}` has a port, so `target_ip` ({}) is ignored",
+                config.target_host, config.target_ip
+            );
+        }
+    }
 
-    info!("Starting rss_r service");
-    execute_command(&session, "sudo systemctl start rss_r")?;
+    true
+}
 
-    info!("Getting status of service");
-    execute_command(&session, "systemctl status rss_r")?;
+fn deploy_to_test_dir(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    check_container_running(config, remote)?;
+    if !args.wipe_persistence {
+        refuse_to_wipe_persistence(config, remote)?;
+    }
 
-    Ok(())
+    // The uploads are removed when they are dropped. They are kept out here, so on a failure
+    // that is only done after pausing.
+    let mut remote_zip = None;
+    let mut staged_config = None;
+    let unpacked = unpack_to_test_dir(config, args, remote, &mut remote_zip, &mut staged_config);
+    pause_if_failed(config, args, remote, unpacked)
 }
 
-fn deploy_to_test_dir(config: &Config) -> Result<()> {
-    let session = connect_and_login(config)?;
-
-    let remote_zip_path = upload_zip_to_tmp_dir(config, &session)?;
+/// Replaces the test directory with the contents of the zip, and puts the test config in it.
+fn unpack_to_test_dir<'a>(
+    config: &Config,
+    args: &Args,
+    remote: &'a Remote,
+    remote_zip: &mut Option<StagedUpload<'a>>,
+    staged_config: &mut Option<StagedUpload<'a>>,
+) -> Result<()> {
+    let remote_zip_path = remote_zip
+        .insert(upload_zip_to_tmp_dir(config, args, remote)?)
+        .path()
+        .to_owned();
 
     info!("Unpacking package to `{}`", config.rss_r_target_test_dir);
-    execute_command(
-        &session,
-        &format!("rm -rf '{}'", config.rss_r_target_test_dir),
+    remote.execute_command(
+        RemoteCommand::new(
+            format!("rm -rf '{}'", config.rss_r_target_test_dir),
+            "Empty the test directory, so nothing of a previous test is left behind.",
+        )
+        .destructive(),
     )?;
-    execute_command(
-        &session,
-        &format!(
-            "unzip '{}' -d '{}'",
-            remote_zip_path, config.rss_r_target_test_dir
-        ),
+    remote.execute_command(
+        RemoteCommand::new(
+            format!(
+                "unzip '{}' -d '{}'",
+                remote_zip_path, config.rss_r_target_test_dir
+            ),
+            "Extract the whole package into the test directory.",
+        )
+        .allow_unzip_warnings(),
     )?;
+    set_created_dir_mode(config, remote, &config.rss_r_target_test_dir)?;
+    *remote_zip = None;
 
     info!("Transferring app config file.");
     let mut config_file_target = config.rss_r_target_test_dir.clone();
     config_file_target.push("rss_r");
     config_file_target.push("persistence");
 
-    execute_command(&session, &format!("mkdir -p '{}'", config_file_target))?;
+    remote.execute_command(RemoteCommand::new(
+        format!("mkdir -p '{}'", config_file_target),
+        "The package does not contain the persistence directory, but the app config goes there.",
+    ))?;
+    set_created_dir_mode(config, remote, &config_file_target)?;
 
     config_file_target.push("app_config.ron");
 
-    upload_file(
-        &session,
-        &config.rss_r_test_config_file,
-        &config_file_target,
-    )?;
+    let explanation =
+        "Use the test config, so the test instance does not interfere with production.";
+    if config.remote_container.is_empty() {
+        remote.upload_file(
+            &config.rss_r_test_config_file,
+            &config_file_target,
+            explanation,
+        )?;
+    } else {
+        // With a `remote_container`, the test directory is only reachable from inside the
+        // container. So it goes through the upload directory.
+        let staged_config =
+            staged_config.insert(StagedUpload::new(config, args, remote, "app_config.ron")?);
+        remote.upload_file(
+            &config.rss_r_test_config_file,
+            staged_config.path(),
+            explanation,
+        )?;
+        remote.execute_command(RemoteCommand::new(
+            format!("mv '{}' '{config_file_target}'", staged_config.path()),
+            "Put the test config in place.",
+        ))?;
+    }
 
     info!("Upload complete.");
 
     Ok(())
 }
 
-/// Returns the path to the uploaded zip.
-fn upload_zip_to_tmp_dir(config: &Config, session: &Session) -> Result<Utf8PathBuf> {
+/// The test directory is emptied on every test deploy. Refuses when it has app state in a
+/// `persistence` directory, like when it points at the data of a real instance by mistake.
+/// The `app_config.ron` is left out, because every test deploy puts one there.
+fn refuse_to_wipe_persistence(config: &Config, remote: &Remote) -> Result<()> {
+    let test_dir = &config.rss_r_target_test_dir;
+    // Both `<test dir>/persistence`, and `<test dir>/rss_r/persistence` like in the zip.
+    let found = remote.execute_command_capture(RemoteCommand::new(
+        format!(
+            "[ ! -d '{test_dir}' ] || find '{test_dir}' -maxdepth 3 -path '*/persistence/*' ! -name app_config.ron | head -n 5"
+        ),
+        "Check that emptying the test directory does not throw away app state.",
+    ))?;
+    if found.trim().is_empty() {
+        return Ok(());
+    }
+
+    for path in found.lines() {
+        warn!("Found app state: `{}`", path);
+    }
+    Err(eyre!(
+        "The test directory `{}` has app state in a `persistence` directory, which the test deploy would throw away. Check that it is the right directory, and use `--wipe-persistence` if the state can go",
+        test_dir
+    ))
+}
+
+/// Sets the configured `created_dir_mode` on a directory we created, if there is one.
+fn set_created_dir_mode(config: &Config, remote: &Remote, dir: &Utf8Path) -> Result<()> {
+    if config.created_dir_mode.is_empty() {
+        return Ok(());
+    }
+
+    remote.execute_command(RemoteCommand::new(
+        format!("chmod '{}' '{}'", config.created_dir_mode, dir),
+        "Don't let the remote umask decide who can read the app state and config.",
+    ))
+}
+
+/// Like [set_created_dir_mode], for directories created with `sudo`. To append to the command
+/// that creates `dir`, empty if there is no `created_dir_mode`.
+fn sudo_chmod_created_dir(config: &Config, dir: &Utf8Path) -> String {
+    if config.created_dir_mode.is_empty() {
+        return String::new();
+    }
+    format!(" && sudo chmod '{}' '{}'", config.created_dir_mode, dir)
+}
+
+/// Command that prints the file at `path`, or nothing if it does not exist. Read with `sudo`,
+/// because the login user may not be allowed in the directory. Unlike `cat` with exit code 1
+/// allowed, a file that can't be read is an error instead of looking like it is not there.
+fn sudo_cat_if_exists(path: &Utf8Path) -> String {
+    format!("! sudo test -e '{path}' || sudo cat '{path}'")
+}
+
+fn upload_zip_to_tmp_dir<'a>(
+    config: &Config,
+    args: &Args,
+    remote: &'a Remote,
+) -> Result<StagedUpload<'a>> {
     info!("Uploading zip to temp directory");
     let package_name = config
         .rss_r_zip
         .file_name()
         .ok_or_eyre("Cannot upload file, path does not have file name.")?;
-    let mut remote_temp_path = Utf8PathBuf::from(REMOTE_TEMP_DIR);
-    remote_temp_path.push(package_name);
+    let upload = StagedUpload::new(config, args, remote, package_name)?;
+    let remote_temp_path = upload.path();
+
+    let explanation = "Upload the package, to be extracted on the target.";
+    let mut retry = 0;
+    loop {
+        if args.resume_upload {
+            remote.upload_file_resumable(&config.rss_r_zip, remote_temp_path, explanation)?;
+        } else {
+            remote.upload_file(&config.rss_r_zip, remote_temp_path, explanation)?;
+        }
+        // A resumed upload is only as good as the partial file it continued, so always check it.
+        if !args.resume_upload && config.max_upload_retries == 0 {
+            break;
+        }
+
+        let Some(remote_hash) = remote.upload_mismatch(&config.rss_r_zip, remote_temp_path)? else {
+            break;
+        };
+        if retry == config.max_upload_retries {
+            return Err(eyre!(
+                "SHA-256 of uploaded `{}` is `{}`, expected `{}`. Remove it and upload again.",
+                remote_temp_path,
+                remote_hash,
+                sha256_file(&config.rss_r_zip)?
+            ));
+        }
+        retry += 1;
+        warn!(
+            "SHA-256 of uploaded `{}` is `{}`, which does not match. Uploading again ({}/{})",
+            remote_temp_path, remote_hash, retry, config.max_upload_retries
+        );
+        remote.execute_command(RemoteCommand::new(
+            format!("rm -f '{remote_temp_path}'"),
+            "Remove the damaged upload, so it is not resumed from.",
+        ))?;
+    }
+
+    Ok(upload)
+}
+
+/// Like `rss_r-20241231T235959Z-1a2b3c.zip` for `rss_r.zip`.
+fn unique_name(file_name: &str) -> Result<String> {
+    let timestamp = OffsetDateTime::now_utc().format(&time::format_description::parse(
+        "[year][month][day]T[hour][minute][second]Z",
+    )?)?;
+    // `RandomState` is seeded randomly, which is all the randomness needed here.
+    let random = RandomState::new().build_hasher().finish() & 0xff_ffff;
+
+    let file_name = Utf8Path::new(file_name);
+    let stem = file_name.file_stem().unwrap_or(file_name.as_str());
+    Ok(match file_name.extension() {
+        Some(extension) => format!("{stem}-{timestamp}-{random:06x}.{extension}"),
+        None => format!("{stem}-{timestamp}-{random:06x}"),
+    })
+}
+
+/// A file uploaded to the upload directory, to be used from there. With
+/// `--include-timestamp-in-zip-name` it has a unique name, so concurrent deploys don't use each
+/// other's files. It is then removed when this is dropped, also when the deploy fails. Otherwise
+/// every deploy would leave another one behind.
+struct StagedUpload<'a> {
+    remote: &'a Remote,
+    path: Utf8PathBuf,
+    unique: bool,
+}
+
+impl<'a> StagedUpload<'a> {
+    fn new(config: &Config, args: &Args, remote: &'a Remote, file_name: &str) -> Result<Self> {
+        let unique = args.include_timestamp_in_zip_name;
+        let path = if unique {
+            config.upload_directory().join(unique_name(file_name)?)
+        } else {
+            config.upload_directory().join(file_name)
+        };
+        Ok(StagedUpload {
+            remote,
+            path,
+            unique,
+        })
+    }
+
+    /// For an upload at a path of its own, made with [unique_name]. Always removed.
+    fn with_unique_path(remote: &'a Remote, path: Utf8PathBuf) -> Self {
+        StagedUpload {
+            remote,
+            path,
+            unique: true,
+        }
+    }
 
-    upload_file(session, &config.rss_r_zip, &remote_temp_path)?;
+    fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
 
-    Ok(remote_temp_path)
+impl Drop for StagedUpload<'_> {
+    fn drop(&mut self) {
+        if !self.unique {
+            return;
+        }
+        let removed = self.remote.execute_command(RemoteCommand::new(
+            format!("rm -f '{}'", self.path),
+            "Remove the upload, it is used up or the deploy failed.",
+        ));
+        if let Err(e) = removed {
+            warn!("Could not remove `{}`: {}", self.path, e);
+        }
+    }
 }
 
-fn run_test_rss_r(config: &Config, session: &Session) -> Result<()> {
+fn test_executable(config: &Config) -> Utf8PathBuf {
     let mut exec_path = config.rss_r_target_test_dir.clone();
     // Top directory in the .zip should be rss_r.
     exec_path.push("rss_r");
     // Executable is also called rss_r.
     exec_path.push("rss_r");
+    exec_path
+}
+
+fn run_test_rss_r(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    let exec_path = test_executable(config);
+    let env_prefix = test_env_prefix(config, args)?;
 
-    let mut working_dir = config.rss_r_target_test_dir.clone();
-    working_dir.push("rss_r");
+    check_for_running_test_instance(remote, &exec_path, args.kill_existing)?;
 
     info!("Running `{}`", exec_path);
     println!("----------");
 
     // Make sure to have the working directory be the same as the rss_r directory,
     // so that the program can locate the persistence and config files properly.
-    execute_command(session, &format!("cd '{}'; '{}'", working_dir, exec_path))
+    remote.execute_command(RemoteCommand::new(
+        format!("cd '{}'; {}'{}'", test_working_dir(config), env_prefix, exec_path),
+        "Run the test instance from its own directory, so it finds its persistence and config files.",
+    ))
 }
 
-fn connect_and_login(config: &Config) -> Result<Session> {
-    let target = config.host_and_port();
-    info!("Connecting to `{}`", target);
-
-    let tcp = TcpStream::connect(&target)
-        .with_context(|| format!("Could not connect to `{}`", target))?;
-    let mut session = Session::new()?;
-
-    session.set_tcp_stream(tcp);
-    session.handshake()?;
-
-    session.userauth_agent(&config.username)?;
-
-    info!("Logged in as `{}`", config.username);
+/// Starts the test instance, and leaves it running in the background. For `--watch`, which
+/// stops it again before the next redeploy.
+fn run_test_rss_r_in_background(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    let exec_path = test_executable(config);
+    let env_prefix = test_env_prefix(config, args)?;
+    let working_dir = test_working_dir(config);
+    let log = working_dir.join("test_instance.log");
+
+    // Without its own output and input, the instance would keep the ssh channel open.
+    remote.execute_command(RemoteCommand::new(
+        format!(
+            "cd '{working_dir}' && {env_prefix}nohup '{exec_path}' > '{log}' 2>&1 < /dev/null &"
+        ),
+        "Start the test instance from its own directory, in the background, so watching goes on.",
+    ))?;
+    info!("The test instance is running, its output goes to `{}`", log);
 
-    Ok(session)
+    Ok(())
 }
 
-/// Executes a given command.
-/// Prints the stdout and stderr output as it arrives.
-/// Returns an error if the command had a non-zero exit code.
-fn execute_command(session: &Session, command: &str) -> Result<()> {
-    // We'll listen to Ctrl+c (SIGINT) while running a command.
-    // So that we can gracefully shut it down.
-    let mut signals = Signals::new([SIGINT])?;
-
-    let mut channel = session.channel_session()?;
-    // Will merge stdout and stderr data into stdout.
-    channel.handle_extended_data(ExtendedData::Merge)?;
-
-    channel.exec(command)?;
-
-    while !channel.eof() {
-        let mut bytes = [0; 32];
-
-        let amount = channel.read(&mut bytes)?;
-        stdout().write_all(&bytes[0..amount])?;
-
-        stdout().flush()?;
-
-        if signals.pending().next().is_some() {
-            // Received interrupt signal.
-            info!("Stopping remote command...");
+fn test_working_dir(config: &Config) -> Utf8PathBuf {
+    config.rss_r_target_test_dir.join("rss_r")
+}
 
-            // Ask the remote to stop the command.
-            // TODO (Wybe 2022-10-17): This does not work yet. How do we stop an ongoing command in this case?
-            channel.send_eof()?;
-            channel.close()?;
-            break;
-        }
+/// `KEY='value' ` for each of the `test_run_env` and `--env` variables, to put in front of the
+/// command that runs the test instance.
+fn test_env_prefix(config: &Config, args: &Args) -> Result<String> {
+    let mut env = config.test_run_env.clone();
+    for variable in &args.env {
+        let (key, value) = variable
+            .split_once('=')
+            .ok_or_else(|| eyre!("`--env {}` should be `KEY=VALUE`", variable))?;
+        env.insert(key.to_string(), value.to_string());
     }
-
-    channel.wait_close()?;
-    let exit_code = channel.exit_status()?;
-
-    if exit_code == 0 {
-        Ok(())
-    } else {
-        Err(eyre!(
-            "command `{}` failed with exit code `{}`",
-            command,
-            exit_code
-        ))
+    let mut env_prefix = String::new();
+    for (key, value) in &env {
+        // The key can't be quoted, so make sure it can't do anything else than set a variable.
+        let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_key {
+            return Err(eyre!("`{}` is not a valid environment variable name", key));
+        }
+        env_prefix.push_str(&format!("{key}={} ", shell_quote(value)));
     }
+    Ok(env_prefix)
 }
 
-fn upload_file(session: &Session, file: &Utf8Path, remote_path: &Utf8Path) -> Result<()> {
-    let mut local_file = File::open(file)?;
-    let mut bytes = Vec::new();
-    local_file.read_to_end(&mut bytes)?;
-
-    info!("Uploading `{}` to `{}`", file, remote_path);
+/// A test instance from an earlier run would keep the port in use, which makes the new one fail
+/// with "address already in use". So refuse to run, or stop the old instance if `kill`.
+fn check_for_running_test_instance(
+    remote: &Remote,
+    exec_path: &Utf8Path,
+    kill: bool,
+) -> Result<()> {
+    // Anchored at the start, so it does not match the shell that runs `pgrep` itself, whose
+    // command line also contains the path.
+    let escaped_path: String = exec_path
+        .as_str()
+        .chars()
+        .map(|c| match c {
+            '.' | '[' | ']' | '(' | ')' | '*' | '+' | '?' | '{' | '}' | '|' | '^' | '$' | '\\' => {
+                format!("\\{c}")
+            }
+            c => c.to_string(),
+        })
+        .collect();
+    let pattern = shell_quote(&format!("^{escaped_path}( |$)"));
+    // `pgrep` and `pkill` exit with 1 when no process matched.
+    let find_instance = RemoteCommand::new(
+        format!("pgrep -f {pattern}"),
+        "Check whether a test instance from an earlier run is still running.",
+    )
+    .allow_exit_codes(&[1]);
 
-    let mut remote_file =
-        session.scp_send(remote_path.as_std_path(), 0o644, bytes.len() as u64, None)?;
+    let pids = remote.execute_command_capture(find_instance.clone())?;
+    let pids = pids.split_whitespace().collect::<Vec<_>>().join(", ");
+    if pids.is_empty() {
+        return Ok(());
+    }
+    if !kill {
+        return Err(eyre!(
+            "A test instance is already running (pid {}). Stop it, or use `--kill-existing`",
+            pids
+        ));
+    }
 
-    remote_file.write_all(&bytes)?;
-    remote_file.send_eof()?;
-    remote_file.wait_eof()?;
-    remote_file.close()?;
-    remote_file.wait_close()?;
+    info!(
+        "Stopping the test instance that is still running (pid {})",
+        pids
+    );
+    remote.execute_command(
+        RemoteCommand::new(
+            format!("pkill -f {pattern}; sleep 1"),
+            "Stop the test instance from an earlier run, so the new one can use the port.",
+        )
+        .allow_exit_codes(&[1])
+        .destructive(),
+    )?;
+    if !remote
+        .execute_command_capture(find_instance)?
+        .trim()
+        .is_empty()
+    {
+        return Err(eyre!("The earlier test instance is still running"));
+    }
 
     Ok(())
 }
 
-fn configure_logging() -> Result<()> {
+/// Listens for Ctrl+c (SIGINT) for the whole run, so that running remote commands can be
+/// gracefully shut down. Pressing Ctrl+c a second time, before the first one has been handled,
+/// exits immediately.
+/// If we can't listen for the signal, we continue without graceful interrupts.
+fn listen_for_interrupt() -> Option<Arc<AtomicBool>> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+
+    let registered = flag::register_conditional_shutdown(SIGINT, 1, Arc::clone(&interrupted))
+        .and_then(|_| flag::register(SIGINT, Arc::clone(&interrupted)));
+
+    match registered {
+        Ok(_) => Some(interrupted),
+        Err(e) => {
+            warn!(
+                "Could not listen for Ctrl+c, remote commands can't be gracefully interrupted: {e}"
+            );
+            None
+        }
+    }
+}
+
+fn configure_logging(level: LevelFilter, color: bool, mode: TerminalMode) -> Result<()> {
     // The logged time is by default in UTC.
     let config = ConfigBuilder::default()
         .set_time_format_custom(format_description!(
@@ -347,11 +1044,14 @@ fn configure_logging() -> Result<()> {
         .build();
 
     TermLogger::init(
-        // TODO (Wybe 2022-07-16): Allow changing this through command line arguments
-        LevelFilter::Info,
+        level,
         config,
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
+        mode,
+        if color {
+            ColorChoice::Auto
+        } else {
+            ColorChoice::Never
+        },
     )
     .context("Could not start logger")
 }
@@ -0,0 +1,132 @@
+use crate::config::Config;
+use crate::live_config::persistence_directory;
+use crate::remote::{shell_quote, Remote, RemoteCommand};
+use camino::Utf8Path;
+use clap::ValueEnum;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::{info, warn};
+
+/// What to do when `--verify-permissions` finds files that don't match the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PermissionCheck {
+    /// Only log the files.
+    Warn,
+    /// Fail the deploy.
+    Fail,
+}
+
+/// Checks the permissions of the deployed files against the policy in the config. Catches
+/// permission drift, like after restoring an old backup.
+pub fn verify_permissions(config: &Config, remote: &Remote, check: PermissionCheck) -> Result<()> {
+    info!("Verifying permissions of the deployed files");
+    let mut violations = Vec::new();
+
+    if !config.executable_mode.is_empty() {
+        let found = find(
+            remote,
+            &config.production_executable(),
+            &format!("! -perm {}", config.executable_mode),
+            "Find the executable, if it does not have the expected permissions.",
+        )?;
+        violations.extend(
+            found
+                .into_iter()
+                .map(|path| format!("`{path}` is not `{}`", config.executable_mode)),
+        );
+    }
+    if !config.static_forbidden_permissions.is_empty() {
+        let found = find(
+            remote,
+            &config.production_static_directory(),
+            &format!("-perm /{}", config.static_forbidden_permissions),
+            "Find static files that can be changed by other users than the service.",
+        )?;
+        violations.extend(found.into_iter().map(|path| {
+            format!(
+                "`{path}` has some of `{}`",
+                config.static_forbidden_permissions
+            )
+        }));
+    }
+    if !config.persistence_forbidden_permissions.is_empty() {
+        let found = find(
+            remote,
+            &persistence_directory(config),
+            &format!("-perm /{}", config.persistence_forbidden_permissions),
+            "Find app state and config that can be read by other users than the service.",
+        )?;
+        violations.extend(found.into_iter().map(|path| {
+            format!(
+                "`{path}` has some of `{}`",
+                config.persistence_forbidden_permissions
+            )
+        }));
+    }
+
+    if violations.is_empty() {
+        info!("All permissions are as expected");
+        return Ok(());
+    }
+
+    for violation in &violations {
+        warn!("Unexpected permissions: {}", violation);
+    }
+    match check {
+        PermissionCheck::Warn => Ok(()),
+        PermissionCheck::Fail => Err(eyre!(
+            "{} deployed file(s) have unexpected permissions",
+            violations.len()
+        )),
+    }
+}
+
+/// Checks that the service user can really use the deployed files, by trying as that user.
+/// Ownership can look right, while ACLs or the permissions of a parent directory still keep
+/// the user out.
+pub fn verify_service_user_access(config: &Config, remote: &Remote) -> Result<()> {
+    info!(
+        "Verifying that {} can use the deployed files",
+        config.rss_r_production_user
+    );
+    let as_user = format!("sudo -u {}", shell_quote(&config.rss_r_production_user));
+    let executable = config.production_executable();
+    let static_dir = config.production_static_directory();
+
+    // Every problem is printed as a line. `find` prints the files it can't read, and the exit
+    // code of the `sed` after it is what counts.
+    let output = remote.execute_command_capture(RemoteCommand::new(
+        format!(
+            "{as_user} test -r '{executable}' -a -x '{executable}' || echo 'cannot execute {executable}'; \
+             {as_user} test -r '{static_dir}' -a -x '{static_dir}' || echo 'cannot read {static_dir}'; \
+             {as_user} find '{static_dir}' ! -readable 2>&1 | sed 's/^/cannot read /'"
+        ),
+        "Try to use the deployed files as the service user, like the service will.",
+    ))?;
+    if remote.is_plan() || output.trim().is_empty() {
+        return Ok(());
+    }
+
+    for problem in output.lines() {
+        warn!("{}: {}", config.rss_r_production_user, problem);
+    }
+    Err(eyre!(
+        "{} can't use all of the deployed files",
+        config.rss_r_production_user
+    ))
+}
+
+/// Returns the files under `path` that match the `find` predicate. Nothing if `path` does not
+/// exist.
+fn find(
+    remote: &Remote,
+    path: &Utf8Path,
+    predicate: &str,
+    explanation: &str,
+) -> Result<Vec<String>> {
+    let output = remote.execute_command_capture(RemoteCommand::new(
+        format!("! sudo test -e '{path}' || sudo find '{path}' {predicate}"),
+        explanation,
+    ))?;
+    Ok(output.lines().map(str::to_string).collect())
+}
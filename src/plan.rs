@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::remote::{in_container, shell_quote, RemoteCommand};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs;
+
+/// Something a deploy does on the target. Recorded when only planning.
+pub enum Step {
+    Command(RemoteCommand),
+    /// A command that takes a lock, and holds it until its stdin is closed.
+    Lock(RemoteCommand),
+    Upload {
+        source: UploadSource,
+        remote_path: Utf8PathBuf,
+        explanation: String,
+    },
+}
+
+pub enum UploadSource {
+    File(Utf8PathBuf),
+    /// Contents generated by this script, like the `DEPLOY_INFO` file.
+    Bytes(Vec<u8>),
+}
+
+impl Step {
+    fn explanation(&self) -> &str {
+        match self {
+            Step::Command(command) | Step::Lock(command) => &command.explanation,
+            Step::Upload { explanation, .. } => explanation,
+        }
+    }
+}
+
+/// Prints the steps, optionally with why each of them is done.
+pub fn print_plan(steps: &[Step], explain: bool) {
+    println!("Planned steps:");
+    for (i, step) in steps.iter().enumerate() {
+        let description = match step {
+            Step::Command(command) | Step::Lock(command) => command.command.clone(),
+            Step::Upload {
+                source: UploadSource::File(file),
+                remote_path,
+                ..
+            } => format!("upload `{file}` to `{remote_path}`"),
+            Step::Upload {
+                source: UploadSource::Bytes(bytes),
+                remote_path,
+                ..
+            } => format!("upload {} generated bytes to `{remote_path}`", bytes.len()),
+        };
+
+        println!("{:>3}. {}", i + 1, description);
+        if explain {
+            println!("     # {}", step.explanation());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonPlan<'a> {
+    target_host: String,
+    steps: Vec<JsonStep<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonStep<'a> {
+    /// 1-based, the same numbers as in `--show-plan`.
+    id: usize,
+    #[serde(flatten)]
+    kind: JsonStepKind<'a>,
+    explanation: &'a str,
+    destructive: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonStepKind<'a> {
+    Command {
+        command: &'a str,
+        allowed_exit_codes: &'a [i32],
+    },
+    Upload {
+        /// `None` for contents generated by this script.
+        local_path: Option<&'a Utf8Path>,
+        remote_path: &'a Utf8Path,
+        size: Option<usize>,
+    },
+}
+
+/// Prints the steps as JSON, for tooling that wants to inspect a deploy before it is done.
+pub fn print_plan_json(steps: &[Step], config: &Config) -> Result<()> {
+    let plan = JsonPlan {
+        target_host: config.host_and_port(),
+        steps: steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let (kind, destructive) = match step {
+                    Step::Command(command) | Step::Lock(command) => (
+                        JsonStepKind::Command {
+                            command: &command.command,
+                            allowed_exit_codes: &command.allowed_exit_codes,
+                        },
+                        command.destructive,
+                    ),
+                    Step::Upload {
+                        source,
+                        remote_path,
+                        ..
+                    } => (
+                        JsonStepKind::Upload {
+                            local_path: match source {
+                                UploadSource::File(file) => Some(file),
+                                UploadSource::Bytes(_) => None,
+                            },
+                            remote_path,
+                            size: match source {
+                                UploadSource::File(_) => None,
+                                UploadSource::Bytes(bytes) => Some(bytes.len()),
+                            },
+                        },
+                        // Uploads only go to the temp directory or the test directory.
+                        false,
+                    ),
+                };
+                JsonStep {
+                    id: i + 1,
+                    kind,
+                    explanation: step.explanation(),
+                    destructive,
+                }
+            })
+            .collect(),
+    };
+
+    println!("{}", serde_json::to_string_pretty(&plan)?);
+    Ok(())
+}
+
+/// The command line to give `ssh`, run in the configured container and shell.
+fn script_command(command: &RemoteCommand, config: &Config) -> String {
+    let mut remote_command = command.command.clone();
+    if !config.remote_container.is_empty() && !command.on_host {
+        remote_command = in_container(&remote_command, &config.remote_container, &config.username);
+    }
+    if !config.remote_shell.is_empty() {
+        remote_command = format!(
+            "{} -c {}",
+            config.remote_shell,
+            shell_quote(&remote_command)
+        );
+    }
+    remote_command
+}
+
+/// Writes the steps as a standalone bash script, that does them over `ssh` and `scp`.
+/// So the deploy can be reviewed, versioned, or done by hand when this program is not available.
+pub fn write_script(steps: &[Step], config: &Config, path: &Utf8Path) -> Result<()> {
+    let mut script = String::new();
+    writeln!(script, "#!/usr/bin/env bash")?;
+    writeln!(
+        script,
+        "# Generated by the rss_r deploy script. Does the same steps as a deploy to `{}`.",
+        config.host_and_port()
+    )?;
+    writeln!(script, "set -euo pipefail")?;
+    writeln!(script)?;
+    writeln!(
+        script,
+        "TARGET={}",
+        shell_quote(&format!("{}@{}", config.username, config.host()))
+    )?;
+    writeln!(script, "PORT={}", config.port())?;
+
+    for step in steps {
+        writeln!(script)?;
+        writeln!(script, "# {}", step.explanation())?;
+
+        match step {
+            Step::Lock(command) => {
+                // Kept running next to the other steps. Its stdin is closed when the script
+                // exits, which releases the lock.
+                writeln!(
+                    script,
+                    "coproc LOCK {{ ssh -p \"$PORT\" \"$TARGET\" {}; }}",
+                    shell_quote(&script_command(command, config))
+                )?;
+                writeln!(
+                    script,
+                    "read -r _ <&\"${{LOCK[0]}}\" || {{ echo 'Could not take the lock' >&2; exit 1; }}"
+                )?;
+            }
+            Step::Command(command) => {
+                let remote_command = script_command(command, config);
+                write!(
+                    script,
+                    "ssh -p \"$PORT\" \"$TARGET\" {}",
+                    shell_quote(&remote_command)
+                )?;
+
+                if !command.allowed_exit_codes.is_empty() {
+                    let codes = command
+                        .allowed_exit_codes
+                        .iter()
+                        .map(|code| code.to_string())
+                        .collect::<Vec<_>>()
+                        .join("|");
+                    write!(script, " || case $? in {codes}) ;; *) exit 1 ;; esac")?;
+                }
+                writeln!(script)?;
+            }
+            Step::Upload {
+                source: UploadSource::File(file),
+                remote_path,
+                ..
+            } => {
+                writeln!(
+                    script,
+                    "scp -P \"$PORT\" {} \"$TARGET\":{}",
+                    shell_quote(file.as_str()),
+                    shell_quote(remote_path.as_str())
+                )?;
+            }
+            Step::Upload {
+                source: UploadSource::Bytes(bytes),
+                remote_path,
+                ..
+            } => {
+                // Generated contents are embedded as they were when the script was written.
+                let contents = String::from_utf8_lossy(bytes);
+                writeln!(
+                    script,
+                    "ssh -p \"$PORT\" \"$TARGET\" {} <<'END_OF_CONTENTS'",
+                    shell_quote(&format!("cat > '{remote_path}'"))
+                )?;
+                writeln!(script, "{}", contents.trim_end())?;
+                writeln!(script, "END_OF_CONTENTS")?;
+            }
+        }
+    }
+
+    fs::write(path, script).with_context(|| format!("Could not write script to `{}`", path))?;
+    make_executable(path)
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Utf8Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Utf8Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use crate::remote::Remote;
+use crate::shell::write_all_non_blocking;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use log::{debug, info};
+use ssh2::Channel;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long to wait when there is nothing to accept or forward.
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+/// A local port that is forwarded to a port on the target.
+#[derive(Debug, Clone, Copy)]
+pub struct PortForward {
+    pub local_port: u16,
+    pub remote_port: u16,
+}
+
+impl PortForward {
+    /// Parses `PORT`, to use the same port on both sides, or `LOCAL_PORT:REMOTE_PORT`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let (local, remote) = value.split_once(':').unwrap_or((value, value));
+        let parse_port = |port: &str| {
+            port.parse::<u16>().with_context(|| {
+                format!(
+                    "`--local-port-forward {value}` should be `PORT` or `LOCAL_PORT:REMOTE_PORT`"
+                )
+            })
+        };
+        Ok(PortForward {
+            local_port: parse_port(local)?,
+            remote_port: parse_port(remote)?,
+        })
+    }
+}
+
+/// A forwarded connection, from a local client to the port on the target.
+struct Connection {
+    client: TcpStream,
+    channel: Channel,
+}
+
+/// Forwards connections to `localhost:<local_port>` to the port on the target, over the ssh
+/// connection. Until Ctrl+c is pressed, or `duration` has passed.
+pub fn forward_local_port(
+    remote: &Remote,
+    forward: PortForward,
+    duration: Option<Duration>,
+) -> Result<()> {
+    let session = remote
+        .session()
+        .ok_or_else(|| eyre!("Cannot forward a port when only planning"))?;
+
+    // Only on the loopback interface, so the service isn't exposed to the local network.
+    let listener = TcpListener::bind(("127.0.0.1", forward.local_port))
+        .with_context(|| format!("Could not listen on local port {}", forward.local_port))?;
+    listener.set_nonblocking(true)?;
+
+    info!(
+        "Port {} on the target is now reachable at http://localhost:{}",
+        forward.remote_port, forward.local_port
+    );
+    match duration {
+        Some(duration) => info!(
+            "Forwarding for {} seconds, or until Ctrl+c is pressed",
+            duration.as_secs()
+        ),
+        None => info!("Forwarding until Ctrl+c is pressed"),
+    }
+
+    let start = Instant::now();
+    let mut connections = Vec::new();
+    while !remote.take_interrupt() && duration.is_none_or(|duration| start.elapsed() < duration) {
+        let mut idle = true;
+
+        match listener.accept() {
+            Ok((client, address)) => {
+                debug!("Forwarding connection from {}", address);
+                // Opening a channel waits for the target to answer, which only works blocking.
+                let channel = session.channel_direct_tcpip(
+                    "localhost",
+                    forward.remote_port,
+                    Some(("127.0.0.1", forward.local_port)),
+                );
+                match channel {
+                    Ok(channel) => {
+                        client.set_nonblocking(true)?;
+                        connections.push(Connection { client, channel });
+                    }
+                    Err(e) => info!(
+                        "Could not connect to port {} on the target: {}",
+                        forward.remote_port, e
+                    ),
+                }
+                idle = false;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        session.set_blocking(false);
+        let mut result = Ok(());
+        let mut closed = Vec::new();
+        for (index, connection) in connections.iter_mut().enumerate() {
+            match forward_available(connection) {
+                Ok((open, forwarded)) => {
+                    idle &= !forwarded;
+                    if !open {
+                        closed.push(index);
+                    }
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        session.set_blocking(true);
+        result?;
+
+        // Closed blocking, so the target gets to know about it.
+        for index in closed.into_iter().rev() {
+            let mut connection = connections.remove(index);
+            let _ = connection.channel.close();
+        }
+
+        if idle {
+            sleep(IDLE_WAIT);
+        }
+    }
+
+    info!("Stopped forwarding port {}", forward.remote_port);
+    Ok(())
+}
+
+/// Forwards what is available in both directions, without waiting for more.
+/// Returns whether the connection is still open, and whether anything was forwarded.
+fn forward_available(connection: &mut Connection) -> Result<(bool, bool)> {
+    let mut bytes = [0; 16 * 1024];
+    let mut forwarded = false;
+
+    match connection.client.read(&mut bytes) {
+        // The client is done.
+        Ok(0) => return Ok((false, true)),
+        Ok(amount) => {
+            write_all_non_blocking(&mut connection.channel, &bytes[..amount])?;
+            forwarded = true;
+        }
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(_) => return Ok((false, true)),
+    }
+
+    match connection.channel.read(&mut bytes) {
+        Ok(amount) if amount > 0 => {
+            write_all_non_blocking(&mut connection.client, &bytes[..amount])?;
+            forwarded = true;
+        }
+        Ok(_) if connection.channel.eof() => return Ok((false, true)),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(_) => return Ok((false, true)),
+    }
+
+    Ok((true, forwarded))
+}
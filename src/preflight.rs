@@ -0,0 +1,240 @@
+use crate::archive::list_zip_files;
+use crate::config::Config;
+use crate::remote::{shell_quote, Remote, RemoteCommand};
+use crate::sudo_chmod_created_dir;
+use camino::Utf8Path;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Runs all the read-only checks before a production deploy, in a single remote script.
+/// Every command is a round-trip to the target, which adds up on a slow connection.
+///
+/// Checks that:
+/// - the `remote_container` is running, if there is one.
+/// - the commands the deploy uses exist on the target.
+/// - the production user exists.
+/// - the production directory exists. On a first deploy it doesn't, and then it is created
+///   if `create_dirs`. That is opt-in, so a typo in the path doesn't silently create a new
+///   directory.
+/// - the filesystem of the production directory has room for everything in the zip. Both in
+///   bytes, and in inodes. A filesystem can run out of inodes while it still has plenty of
+///   bytes left, for example when it holds many small files.
+pub fn run_preflight_checks(config: &Config, remote: &Remote, create_dirs: bool) -> Result<()> {
+    check_container_running(config, remote)?;
+
+    let directory = &config.rss_r_production_directory;
+    let user = &config.rss_r_production_user;
+
+    let mut commands = vec!["unzip", "sha256sum", "systemctl"];
+    if !config.health_check_url.is_empty() {
+        commands.push("curl");
+    }
+
+    // Every check prints a `key=value` line.
+    let mut script = String::from("sh <<'END_OF_PREFLIGHT'\n");
+    for command in &commands {
+        writeln!(
+            script,
+            "command -v {command} >/dev/null && echo command_{command}=yes || echo command_{command}=no"
+        )?;
+    }
+    writeln!(
+        script,
+        "id -u {} >/dev/null 2>&1 && echo user_exists=yes || echo user_exists=no",
+        shell_quote(user)
+    )?;
+    writeln!(
+        script,
+        "sudo test -d {} && echo directory_exists=yes || echo directory_exists=no",
+        shell_quote(directory.as_str())
+    )?;
+    // If the directory does not exist yet, it will be created on the filesystem of its
+    // closest existing parent.
+    writeln!(script, "d={}", shell_quote(directory.as_str()))?;
+    writeln!(
+        script,
+        "while [ ! -d \"$d\" ]; do d=$(dirname \"$d\"); done"
+    )?;
+    writeln!(script, "echo \"blocks=$(df -Pk \"$d\" | tail -n 1)\"")?;
+    writeln!(script, "echo \"inodes=$(df -Pi \"$d\" | tail -n 1)\"")?;
+    script.push_str("END_OF_PREFLIGHT");
+
+    let output = remote.execute_command_capture(RemoteCommand::new(
+        script,
+        "Check that the target is ready for the deploy: needed commands, the production user, the production directory, and free disk space and inodes.",
+    ))?;
+    if remote.is_plan() {
+        if create_dirs {
+            create_production_directory(config, remote)?;
+        }
+        return Ok(());
+    }
+
+    let results: BTreeMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+    let result = |key: &str| {
+        results.get(key).copied().ok_or_else(|| {
+            eyre!(
+                "Pre-flight check did not report `{}`: `{}`",
+                key,
+                output.trim()
+            )
+        })
+    };
+
+    let missing_commands = commands
+        .iter()
+        .filter(|command| results.get(format!("command_{command}").as_str()) != Some(&"yes"))
+        .map(|command| format!("`{command}`"))
+        .collect::<Vec<_>>();
+    if !missing_commands.is_empty() {
+        return Err(eyre!(
+            "These commands are not available on the target: {}",
+            missing_commands.join(", ")
+        ));
+    }
+
+    if result("user_exists")? != "yes" {
+        return Err(eyre!(
+            "Production user `{}` does not exist on the target",
+            user
+        ));
+    }
+
+    if result("directory_exists")? != "yes" {
+        if !create_dirs {
+            return Err(eyre!(
+                "Production directory `{}` does not exist. If this is the first deploy, use `--create-dirs` to create it",
+                directory
+            ));
+        }
+        create_production_directory(config, remote)?;
+        info!("Production directory `{}` created", directory);
+    }
+
+    check_free_space(config, result("blocks")?, result("inodes")?)
+}
+
+/// Checks that the `remote_container` exists and is running, if there is one. Otherwise every
+/// command would fail with a less clear error.
+pub fn check_container_running(config: &Config, remote: &Remote) -> Result<()> {
+    let container = &config.remote_container;
+    if container.is_empty() {
+        return Ok(());
+    }
+
+    // `machinectl` only knows about running containers, and exits with 1 for others.
+    let state = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!(
+                "machinectl show -p State --value {}",
+                shell_quote(container)
+            ),
+            "Check that the container to deploy into exists and is running.",
+        )
+        .on_host()
+        .allow_exit_codes(&[1]),
+    )?;
+    if remote.is_plan() {
+        return Ok(());
+    }
+
+    match state.trim() {
+        "running" => Ok(()),
+        "" => Err(eyre!(
+            "Container `{}` does not exist on the target, or is not running",
+            container
+        )),
+        other => Err(eyre!(
+            "Container `{}` on the target is `{}`, instead of running",
+            container,
+            other
+        )),
+    }
+}
+
+fn create_production_directory(config: &Config, remote: &Remote) -> Result<()> {
+    let directory = &config.rss_r_production_directory;
+    let user = &config.rss_r_production_user;
+    let chmod = sudo_chmod_created_dir(config, directory);
+
+    remote.execute_command(RemoteCommand::new(
+        format!("sudo mkdir -p '{directory}' && sudo chown '{user}':'{user}' '{directory}'{chmod}"),
+        "Create the production directory, because this is the first deploy.",
+    ))
+}
+
+/// `blocks` and `inodes` are the lines for the production directory from `df -Pk` and `df -Pi`.
+fn check_free_space(config: &Config, blocks: &str, inodes: &str) -> Result<()> {
+    let files = list_zip_files(&config.rss_r_zip)?;
+    let needed_bytes: u64 = files.values().map(|entry| entry.size).sum();
+    let needed_inodes = files.len() as u64;
+
+    let directory = &config.rss_r_production_directory;
+    let (_, free_kilobytes) = parse_df(blocks)?;
+    let free_bytes = free_kilobytes * 1024;
+    let (total_inodes, free_inodes) = parse_df(inodes)?;
+
+    info!(
+        "Pre-flight: `{}` has {} free ({} needed), and {} free inodes ({} needed)",
+        directory,
+        format_bytes(free_bytes),
+        format_bytes(needed_bytes),
+        free_inodes,
+        needed_inodes
+    );
+
+    if free_bytes < needed_bytes {
+        return Err(not_enough(
+            directory,
+            &format_bytes(free_bytes),
+            &format_bytes(needed_bytes),
+            "disk space",
+        ));
+    }
+    // Some filesystems, like btrfs, don't have a fixed number of inodes and report 0.
+    if total_inodes > 0 && free_inodes < needed_inodes {
+        return Err(not_enough(
+            directory,
+            &free_inodes.to_string(),
+            &needed_inodes.to_string(),
+            "inodes",
+        ));
+    }
+
+    Ok(())
+}
+
+fn not_enough(directory: &Utf8Path, free: &str, needed: &str, what: &str) -> color_eyre::Report {
+    eyre!(
+        "Not enough {} for `{}`: {} free, {} needed",
+        what,
+        directory,
+        free,
+        needed
+    )
+}
+
+/// Returns the total and available columns from a line of `df -P` output, which are in 1K
+/// blocks or inodes, depending on whether `-i` was given.
+fn parse_df(line: &str) -> Result<(u64, u64)> {
+    // Columns are: filesystem, total, used, available, capacity, mount.
+    let columns: Vec<&str> = line.split_whitespace().collect();
+
+    match (
+        columns.get(1).and_then(|total| total.parse().ok()),
+        columns.get(3).and_then(|available| available.parse().ok()),
+    ) {
+        (Some(total), Some(available)) => Ok((total, available)),
+        _ => Err(eyre!("Unexpected output from `df`: `{}`", line.trim())),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -0,0 +1,875 @@
+use crate::additional_archives::deploy_additional_archives;
+use crate::archive::{
+    list_zip_files, sha256_file, sha256_zip_entry, RSS_R_EXEC_IN_ZIP, STATIC_DIR_IN_ZIP,
+};
+use crate::changelog::read_deployed_info;
+use crate::config::Config;
+use crate::deploy_info::{DeployInfo, DEPLOY_INFO_FILE};
+use crate::health::wait_until_healthy;
+use crate::keep_open::{pause_if_failed, pause_on_failure};
+use crate::live_config::{install_production_config, live_config_path, stage_production_config};
+use crate::permissions::{verify_permissions, verify_service_user_access};
+use crate::preflight::run_preflight_checks;
+use crate::remote::{sudo_systemctl, systemctl, Remote, RemoteCommand, RemoteLock};
+use crate::service::{check_available_memory, show_status_text, ServiceStatus};
+use crate::{sudo_cat_if_exists, upload_zip_to_tmp_dir, Args, StagedUpload};
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::eyre;
+use color_eyre::{eyre::WrapErr, Result};
+use log::{info, warn};
+
+/// Where systemd looks for unit files that were installed by the administrator.
+const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+/// Holds the SHA-256 of the zip while it is being deployed, after the backups are made.
+const DEPLOY_IN_PROGRESS_FILE: &str = "DEPLOY_IN_PROGRESS";
+
+/// Deploys the zip to production, with a backup to roll back to.
+///
+/// Running it again after it failed or was interrupted is safe. Directories are created with
+/// `mkdir -p`, files are extracted with `unzip -o`, and the backups of the version before this
+/// zip are kept. The live config is only backed up when it differs from the new one.
+pub fn deploy_production(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    if config.sudo_requires_password {
+        remote.prime_sudo(&config.username)?;
+    }
+
+    run_preflight_checks(config, remote, args.create_dirs)?;
+    let _lock = take_remote_lock(config, remote)?;
+
+    // The uploads are removed when they are dropped. They are kept out here, so on a failure
+    // that is only done after pausing.
+    let mut remote_zip = None;
+    let mut staged_config = None;
+    let deployed = install_new_version(config, args, remote, &mut remote_zip, &mut staged_config);
+    pause_if_failed(config, args, remote, deployed)
+}
+
+/// Stops the service, replaces the executable and static files, and starts it again. Rolls back
+/// if the new version does not pass verification.
+fn install_new_version<'a>(
+    config: &Config,
+    args: &Args,
+    remote: &'a Remote,
+    remote_zip: &mut Option<StagedUpload<'a>>,
+    staged_config: &mut Option<StagedUpload<'a>>,
+) -> Result<()> {
+    let remote_zip_path = remote_zip
+        .insert(upload_zip_to_tmp_dir(config, args, remote)?)
+        .path()
+        .to_owned();
+
+    info!("Check if zip contains expected files");
+    let rss_r_exec_in_zip = Utf8PathBuf::from(RSS_R_EXEC_IN_ZIP);
+    let static_dir_in_zip = Utf8PathBuf::from(STATIC_DIR_IN_ZIP);
+
+    remote
+        .execute_command(RemoteCommand::new(
+            format!(
+                "unzip -l '{}' | grep -q '{}'",
+                remote_zip_path, rss_r_exec_in_zip
+            ),
+            "Make sure the zip contains an executable, before the service is stopped.",
+        ))
+        .with_context(|| format!("Zip does not contain `{}`", rss_r_exec_in_zip))?;
+    remote
+        .execute_command(RemoteCommand::new(
+            format!(
+                "unzip -l '{}' | grep -q '{}'",
+                remote_zip_path, static_dir_in_zip
+            ),
+            "Make sure the zip contains a static directory, before the service is stopped.",
+        ))
+        .with_context(|| format!("Zip does not contain `{}`", static_dir_in_zip))?;
+    info!("Expected files found");
+
+    *staged_config = stage_production_config(config, remote, args.yes)?;
+
+    let service = &config.service_name;
+    let unit_file = unit_file_source(config)?;
+    // Changes to the unit, like a different `ExecStart`, need a restart to take effect.
+    let reload =
+        config.reload_instead_of_restart && unit_file.is_none() && can_reload(config, remote)?;
+    if !reload {
+        info!("Stopping {} service", service);
+        remote.execute_command(
+            RemoteCommand::new(
+                sudo_systemctl(&format!("stop '{service}'")),
+                "Stop the service, so the executable can be replaced while it is not running.",
+            )
+            .destructive(),
+        )?;
+    }
+
+    let target_rss_exe = config.production_executable();
+    let target_static_dir = config.production_static_directory();
+    back_up_previous_version(config, remote)?;
+
+    if reload {
+        info!("Extracting static directory");
+    } else {
+        info!("Extracting rss_r exe and static directory");
+        // `-j`: unzip only the files specified, do not create their parent directories.
+        // `-o`: Overwrite files without prompting.
+        remote.execute_command(
+            RemoteCommand::new(
+                format!(
+                    "sudo unzip -j -o '{remote_zip_path}' '{rss_r_exec_in_zip}' -d {}",
+                    config.rss_r_production_directory
+                ),
+                "Extract the new executable over the old one.",
+            )
+            .allow_unzip_warnings()
+            .destructive(),
+        )?;
+    }
+    let exclude_args = static_exclude_args(config, remote, &remote_zip_path, &static_dir_in_zip)?;
+    remote.execute_command(
+        RemoteCommand::new(
+            format!(
+                "sudo unzip -j -o '{}' '{}*'{} -d {}",
+                remote_zip_path, static_dir_in_zip, exclude_args, target_static_dir
+            ),
+            "Extract the new static directory.",
+        )
+        .allow_unzip_warnings()
+        .destructive(),
+    )?;
+
+    info!("Setting ownership to {}", config.rss_r_production_user);
+    remote.execute_command(RemoteCommand::new(
+        chown_command(&config.rss_r_production_user, &target_rss_exe, false),
+        "Extracted files are owned by root, give the executable to the user the service runs as.",
+    ))?;
+    remote.execute_command(RemoteCommand::new(
+        chown_command(&config.rss_r_production_user, &target_static_dir, true),
+        "Give the static files to the user the service runs as, so it can read them.",
+    ))?;
+
+    deploy_additional_archives(config, args, remote)?;
+
+    if let Some(unit_file) = &unit_file {
+        install_unit_file(config, args, remote, unit_file, &remote_zip_path)?;
+    }
+    *remote_zip = None;
+
+    let config_backup = match staged_config {
+        Some(staged_config) => install_production_config(config, remote, staged_config.path())?,
+        None => None,
+    };
+
+    if reload {
+        info!("Reloading {} service", service);
+        remote.execute_command(RemoteCommand::new(
+            sudo_systemctl(&format!("reload '{service}'")),
+            "Let the running service pick up the new static files and config, without downtime.",
+        ))?;
+    } else {
+        check_available_memory(config, remote)?;
+        info!("Starting {} service", service);
+        remote.execute_command(RemoteCommand::new(
+            sudo_systemctl(&format!("start '{service}'")),
+            "Start the service again, now running the new executable.",
+        ))?;
+    }
+
+    let verified = verify_running_executable(config, remote)
+        .and_then(|_| wait_until_healthy(config, remote))
+        .and_then(|_| {
+            if args.verify_access {
+                verify_service_user_access(config, remote)
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|_| match args.verify_permissions {
+            Some(check) => verify_permissions(config, remote, check),
+            None => Ok(()),
+        });
+    if let Err(e) = verified {
+        pause_on_failure(config, args, remote, &e);
+        roll_back(config, remote, config_backup.as_deref())?;
+        return Err(e.wrap_err(
+            "The new version did not pass verification, rolled back to the previous one",
+        ));
+    }
+
+    write_deploy_info(config, args, remote)?;
+
+    Ok(())
+}
+
+/// Deploys only the given files from the zip, for hotfixes. Paths are as in the zip, like
+/// `rss_r/static/index.html`. The static directory is not emptied first, and the service is
+/// only restarted if the executable is one of the files.
+pub fn deploy_files(config: &Config, args: &Args, remote: &Remote, files: &[String]) -> Result<()> {
+    let files_in_zip = list_zip_files(&config.rss_r_zip)?;
+    for file in files {
+        if !files_in_zip.contains_key(file) {
+            return Err(eyre!("`{}` does not contain `{}`", config.rss_r_zip, file));
+        }
+        if file != RSS_R_EXEC_IN_ZIP && !file.starts_with(STATIC_DIR_IN_ZIP) {
+            return Err(eyre!(
+                "`{}` is not deployed to production, only `{}` and the files in `{}` are",
+                file,
+                RSS_R_EXEC_IN_ZIP,
+                STATIC_DIR_IN_ZIP
+            ));
+        }
+    }
+
+    if config.sudo_requires_password {
+        remote.prime_sudo(&config.username)?;
+    }
+    let _lock = take_remote_lock(config, remote)?;
+
+    // Kept out here, so on a failure the zip is only removed after pausing.
+    let mut remote_zip = None;
+    let deployed = replace_files(config, args, remote, files, &mut remote_zip);
+    pause_if_failed(config, args, remote, deployed)
+}
+
+/// Extracts the `files` over the deployed ones, stopping the service if the executable is one
+/// of them.
+fn replace_files<'a>(
+    config: &Config,
+    args: &Args,
+    remote: &'a Remote,
+    files: &[String],
+    remote_zip: &mut Option<StagedUpload<'a>>,
+) -> Result<()> {
+    let remote_zip_path = remote_zip
+        .insert(upload_zip_to_tmp_dir(config, args, remote)?)
+        .path()
+        .to_owned();
+
+    let service = &config.service_name;
+    let replaces_executable = files.iter().any(|file| file == RSS_R_EXEC_IN_ZIP);
+    if replaces_executable {
+        info!("Stopping {} service", service);
+        remote.execute_command(
+            RemoteCommand::new(
+                sudo_systemctl(&format!("stop '{service}'")),
+                "Stop the service, so the executable can be replaced while it is not running.",
+            )
+            .destructive(),
+        )?;
+    }
+
+    let user = &config.rss_r_production_user;
+    for file in files {
+        // Static files are put directly in the static directory, like in a full deploy.
+        let target_dir = if file == RSS_R_EXEC_IN_ZIP {
+            config.rss_r_production_directory.clone()
+        } else {
+            config.production_static_directory()
+        };
+        let file_name = Utf8Path::new(file)
+            .file_name()
+            .ok_or_else(|| eyre!("`{}` does not have a file name", file))?;
+
+        info!("Deploying `{}` to `{}`", file, target_dir);
+        remote.execute_command(
+            RemoteCommand::new(
+                format!("sudo unzip -j -o '{remote_zip_path}' '{file}' -d '{target_dir}'"),
+                "Extract only this file, over the deployed one.",
+            )
+            .allow_unzip_warnings()
+            .destructive(),
+        )?;
+        remote.execute_command(RemoteCommand::new(
+            chown_command(user, &target_dir.join(file_name), false),
+            "Extracted files are owned by root, give the file to the user the service runs as.",
+        ))?;
+    }
+
+    *remote_zip = None;
+
+    if replaces_executable {
+        check_available_memory(config, remote)?;
+        info!("Starting {} service", service);
+        remote.execute_command(RemoteCommand::new(
+            sudo_systemctl(&format!("start '{service}'")),
+            "Start the service again, now running the new executable.",
+        ))?;
+        // There are no backups to roll back to, like in a full deploy.
+        verify_running_executable(config, remote)
+            .and_then(|_| wait_until_healthy(config, remote))?;
+    }
+
+    if let Some(check) = args.verify_permissions {
+        verify_permissions(config, remote, check)?;
+    }
+    if args.verify_access {
+        verify_service_user_access(config, remote)?;
+    }
+
+    info!("Deployed {} file(s)", files.len());
+    Ok(())
+}
+
+/// Gives `path` to `user`. When `path` is a symlink, like a `current` link to a release
+/// directory, both the link and what it points to are given to the user. A plain `chown -R`
+/// only changes the link itself.
+fn chown_command(user: &str, path: &Utf8Path, recursive: bool) -> String {
+    let recursive = if recursive { " -R" } else { "" };
+    format!(
+        "sudo chown -h '{user}':'{user}' '{path}' && sudo chown{recursive} '{user}':'{user}' \"$(readlink -f '{path}')\""
+    )
+}
+
+/// Where the systemd unit file of the service comes from, if it is deployed.
+enum UnitFile {
+    /// The configured `deploy_unit_file`.
+    Local(Utf8PathBuf),
+    /// The path of the unit file in the zip.
+    InZip(String),
+}
+
+/// The configured `deploy_unit_file` has priority over one in the zip.
+fn unit_file_source(config: &Config) -> Result<Option<UnitFile>> {
+    if !config.deploy_unit_file.as_str().is_empty() {
+        return Ok(Some(UnitFile::Local(config.deploy_unit_file.clone())));
+    }
+
+    let unit_file_in_zip = format!("rss_r/{}.service", config.service_name);
+    Ok(list_zip_files(&config.rss_r_zip)?
+        .contains_key(&unit_file_in_zip)
+        .then_some(UnitFile::InZip(unit_file_in_zip)))
+}
+
+/// Installs the unit file of the service, and lets systemd load it. Otherwise starting the
+/// service would still use the old unit.
+/// The unit file is not rolled back if the new version fails to start.
+fn install_unit_file(
+    config: &Config,
+    args: &Args,
+    remote: &Remote,
+    unit_file: &UnitFile,
+    remote_zip_path: &Utf8Path,
+) -> Result<()> {
+    let service = &config.service_name;
+    let unit_file_name = format!("{service}.service");
+    let target = Utf8PathBuf::from(SYSTEMD_UNIT_DIR).join(&unit_file_name);
+
+    info!("Installing unit file `{}`", target);
+    match unit_file {
+        UnitFile::Local(file) => {
+            let staged = StagedUpload::new(config, args, remote, &unit_file_name)?;
+            let replaced = remote
+                .upload_file(file, staged.path(), "Upload the unit file of the service.")
+                .and_then(|_| {
+                    remote.execute_command(
+                        RemoteCommand::new(
+                            format!("sudo mv '{}' '{target}'", staged.path()),
+                            "Replace the unit file of the service.",
+                        )
+                        .destructive(),
+                    )
+                });
+            pause_if_failed(config, args, remote, replaced)?;
+        }
+        UnitFile::InZip(file_in_zip) => {
+            remote.execute_command(
+                RemoteCommand::new(
+                    format!("sudo unzip -j -o '{remote_zip_path}' '{file_in_zip}' -d '{SYSTEMD_UNIT_DIR}'"),
+                    "Extract the unit file of the service over the old one.",
+                )
+                .allow_unzip_warnings()
+                .destructive(),
+            )?;
+        }
+    }
+    remote.execute_command(RemoteCommand::new(
+        format!("sudo chown root:root '{target}' && sudo chmod 644 '{target}'"),
+        "Unit files should only be writable by root.",
+    ))?;
+
+    remote.execute_command(RemoteCommand::new(
+        sudo_systemctl("daemon-reload"),
+        "Let systemd load the new unit file, so starting the service uses it.",
+    ))?;
+    let needs_reload = remote.execute_command_capture(RemoteCommand::new(
+        systemctl(&format!("show -p NeedDaemonReload --value '{service}'")),
+        "Check that systemd loaded the new unit file.",
+    ))?;
+    if !remote.is_plan() && needs_reload.trim() != "no" {
+        return Err(eyre!(
+            "systemd did not load the new unit file of the {} service",
+            service
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the deploy can be done with `systemctl reload`. Which is only the case if the
+/// executable did not change, and the service supports reloading.
+fn can_reload(config: &Config, remote: &Remote) -> Result<bool> {
+    let service = &config.service_name;
+
+    let deployed_hash = sudo_sha256(
+        remote,
+        &config.production_executable(),
+        "Check whether the executable changed, because then a reload is not enough.",
+    )?;
+    let new_hash = sha256_zip_entry(&config.rss_r_zip, RSS_R_EXEC_IN_ZIP)?;
+    if !remote.is_plan() && deployed_hash.as_ref() != Some(&new_hash) {
+        info!(
+            "The executable changed, so the {} service is restarted instead of reloaded",
+            service
+        );
+        return Ok(false);
+    }
+
+    let can_reload = remote.execute_command_capture(RemoteCommand::new(
+        systemctl(&format!("show -p CanReload --value '{service}'")),
+        "Check whether the service has an `ExecReload`.",
+    ))?;
+    if !remote.is_plan() && can_reload.trim() != "yes" {
+        warn!(
+            "The {} service does not support reloading, restarting it instead",
+            service
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Returns the SHA-256 of a file on the target, read with `sudo` so it also works for files of
+/// other users. `None` if the file does not exist, or when only planning.
+fn sudo_sha256(remote: &Remote, path: &Utf8Path, explanation: &str) -> Result<Option<String>> {
+    // `sha256sum` exits with 1 if the file does not exist.
+    let output = remote.execute_command_capture(
+        RemoteCommand::new(format!("sudo sha256sum '{path}'"), explanation).allow_exit_codes(&[1]),
+    )?;
+
+    // Output is `<hash>  <path>`.
+    Ok(output.split_whitespace().next().map(str::to_string))
+}
+
+/// Keeps the current executable and static directory around as `<name>.previous`, so we can
+/// roll back to them if the new version doesn't come up.
+///
+/// The old static directory is moved away, instead of copied. It needs to be gone anyway, to
+/// make sure there are no old files left behind. Because the `unzip` command will only add or
+/// overwrite files.
+///
+/// When this zip was already deployed, or an earlier deploy was interrupted, the backups are of
+/// the version before that, and are kept. Backing up again would replace them with the files of
+/// this zip, or the half-deployed files of the interrupted one.
+fn back_up_previous_version(config: &Config, remote: &Remote) -> Result<()> {
+    let target_rss_exe = config.production_executable();
+    let target_static_dir = config.production_static_directory();
+    let previous_exe = previous_path(&target_rss_exe);
+    let previous_static_dir = previous_path(&target_static_dir);
+
+    if is_redeploy(config, remote)? {
+        info!(
+            "This zip was already deployed, or an earlier deploy was interrupted. Keeping the backups of the version before it"
+        );
+        remote.execute_command(
+            RemoteCommand::new(
+                format!("sudo rm -rf '{target_static_dir}'"),
+                "Remove the static directory of the earlier attempt, it is extracted again.",
+            )
+            .destructive(),
+        )?;
+        return Ok(());
+    }
+
+    info!("Backing up the current executable and static directory");
+    // On a first deploy, there is nothing to back up.
+    remote.execute_command(RemoteCommand::new(
+        format!("! sudo test -e '{target_rss_exe}' || sudo cmp -s '{target_rss_exe}' '{previous_exe}' || sudo cp -p '{target_rss_exe}' '{previous_exe}'"),
+        "Back up the current executable, to roll back to if the new one does not start. Skipped if the backup is already identical.",
+    ))?;
+    remote.execute_command(RemoteCommand::new(
+        format!("! sudo test -e '{target_static_dir}' || {{ sudo rm -rf '{previous_static_dir}' && sudo mv '{target_static_dir}' '{previous_static_dir}'; }}"),
+        "Move the old static directory out of the way. Unzipping only adds or overwrites files, so otherwise stale files would be left behind.",
+    )
+    .destructive())?;
+
+    let zip_sha256 = sha256_file(&config.rss_r_zip)?;
+    remote.execute_command(RemoteCommand::new(
+        format!(
+            "echo '{zip_sha256}' | sudo tee '{}' > /dev/null",
+            in_progress_path(config)
+        ),
+        "Remember that a deploy is in progress, so a retry after a failure keeps the backups.",
+    ))?;
+
+    Ok(())
+}
+
+/// Whether this zip is already deployed, or an earlier deploy, of any zip, stopped after making
+/// the backups. Only if the backups are still there, after a roll back they are gone.
+fn is_redeploy(config: &Config, remote: &Remote) -> Result<bool> {
+    let zip_sha256 = sha256_file(&config.rss_r_zip)?;
+    let previous_exe = previous_path(&config.production_executable());
+    let previous_static_dir = previous_path(&config.production_static_directory());
+
+    // `test` exits with 1 if neither exists.
+    let backups = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!("sudo test -e '{previous_exe}' -o -e '{previous_static_dir}' && echo yes"),
+            "Check whether there are backups to keep.",
+        )
+        .allow_exit_codes(&[1]),
+    )?;
+    if !remote.is_plan() && backups.trim() != "yes" {
+        return Ok(false);
+    }
+
+    let deployed = read_deployed_info(config, remote)?;
+    let in_progress = remote.execute_command_capture(RemoteCommand::new(
+        sudo_cat_if_exists(&in_progress_path(config)),
+        "Check whether an earlier deploy was interrupted.",
+    ))?;
+
+    Ok(
+        deployed.is_some_and(|info| info.zip_sha256 == zip_sha256)
+            || !in_progress.trim().is_empty(),
+    )
+}
+
+fn in_progress_path(config: &Config) -> Utf8PathBuf {
+    config
+        .rss_r_production_directory
+        .join(DEPLOY_IN_PROGRESS_FILE)
+}
+
+/// Puts the backed up executable, static directory and app config back, and restarts the service.
+fn roll_back(config: &Config, remote: &Remote, config_backup: Option<&Utf8Path>) -> Result<()> {
+    let service = &config.service_name;
+
+    info!("Rolling back to the previous version");
+    remote.execute_command(
+        RemoteCommand::new(
+            sudo_systemctl(&format!("stop '{service}'")),
+            "Stop the failed new version.",
+        )
+        .destructive(),
+    )?;
+    restore_previous_version(config, remote)?;
+    if let Some(config_backup) = config_backup {
+        let live_config = live_config_path(config);
+        remote.execute_command(
+            RemoteCommand::new(
+                format!("sudo cp -p '{config_backup}' '{live_config}'"),
+                "Put the previous app config back.",
+            )
+            .destructive(),
+        )?;
+    }
+    remote.execute_command(RemoteCommand::new(
+        sudo_systemctl(&format!("start '{service}'")),
+        "Start the previous version again.",
+    ))?;
+
+    Ok(())
+}
+
+/// Moves the backed up executable and static directory back in place. The backups are used up
+/// by that, so the deploy is no longer in progress: a retry has to make new ones.
+fn restore_previous_version(config: &Config, remote: &Remote) -> Result<()> {
+    let target_rss_exe = config.production_executable();
+    let target_static_dir = config.production_static_directory();
+    let previous_exe = previous_path(&target_rss_exe);
+    let previous_static_dir = previous_path(&target_static_dir);
+
+    remote.execute_command(
+        RemoteCommand::new(
+            format!(
+                "! sudo test -e '{previous_exe}' || sudo mv -f '{previous_exe}' '{target_rss_exe}'"
+            ),
+            "Put the previous executable back.",
+        )
+        .destructive(),
+    )?;
+    remote.execute_command(RemoteCommand::new(
+        format!(
+            "! sudo test -e '{previous_static_dir}' || {{ sudo rm -rf '{target_static_dir}' && sudo mv '{previous_static_dir}' '{target_static_dir}'; }}"
+        ),
+        "Put the previous static directory back.",
+    )
+    .destructive())?;
+    remote.execute_command(RemoteCommand::new(
+        format!("sudo rm -f '{}'", in_progress_path(config)),
+        "The backups are used up, so a retry should make new ones.",
+    ))?;
+
+    Ok(())
+}
+
+/// Takes the `remote_lock`, if it is configured. Held until the returned guard is dropped, at the
+/// end of the deploy. Also when it fails, until after the roll back.
+fn take_remote_lock<'a>(config: &Config, remote: &'a Remote) -> Result<Option<RemoteLock<'a>>> {
+    if !config.remote_lock {
+        return Ok(None);
+    }
+    remote
+        .lock(
+            &config.production_lock_file(),
+            config.remote_lock_timeout_secs,
+        )
+        .map(Some)
+}
+
+fn previous_path(path: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{path}.previous"))
+}
+
+/// Checks that the service is running. And because `systemctl` can report a service as active
+/// while it still runs an old process, for example when the executable could not be replaced,
+/// that the main process of the service really is the executable we just deployed.
+fn verify_running_executable(config: &Config, remote: &Remote) -> Result<()> {
+    let service = &config.service_name;
+    let target_rss_exe = config.production_executable();
+
+    let Some(status) = ServiceStatus::query(config, remote)? else {
+        return Ok(());
+    };
+    info!("Service `{}` is {}", service, status);
+    if !status.is_running() {
+        show_status_text(config, remote)?;
+        return Err(eyre!("Service `{}` is not running: {}", service, status));
+    }
+    let main_pid = status.main_pid;
+
+    let running_exe = remote.execute_command_capture(RemoteCommand::new(
+        format!("sudo readlink '/proc/{main_pid}/exe'"),
+        "Find which executable the service's process is running.",
+    ))?;
+    let deployed_exe = remote.execute_command_capture(RemoteCommand::new(
+        format!("sudo readlink -f '{target_rss_exe}'"),
+        "Resolve the deployed executable, to compare it with the running one.",
+    ))?;
+    // If the file was replaced after the process started, the link ends in ` (deleted)`.
+    if running_exe.trim() != deployed_exe.trim() {
+        return Err(eyre!(
+            "Service `{}` is running `{}`, not the deployed `{}`",
+            service,
+            running_exe.trim(),
+            deployed_exe.trim()
+        ));
+    }
+
+    let running_hash = sudo_sha256(
+        remote,
+        Utf8Path::new(&format!("/proc/{main_pid}/exe")),
+        "Compare the running executable with the one in the zip.",
+    )?
+    .ok_or_else(|| eyre!("Could not read the executable of process {}", main_pid))?;
+    let expected_hash = sha256_zip_entry(&config.rss_r_zip, RSS_R_EXEC_IN_ZIP)?;
+    if running_hash != expected_hash {
+        return Err(eyre!(
+            "The running executable has SHA-256 `{}`, but the one in the zip has `{}`",
+            running_hash,
+            expected_hash
+        ));
+    }
+
+    info!(
+        "Service `{}` is running the new executable (pid {})",
+        service, main_pid
+    );
+    Ok(())
+}
+
+/// Returns the ` -x ...` arguments for `unzip` that leave out the `static_exclude_globs`.
+/// Logs which files in the zip will be left out.
+fn static_exclude_args(
+    config: &Config,
+    remote: &Remote,
+    remote_zip_path: &Utf8Path,
+    static_dir_in_zip: &Utf8Path,
+) -> Result<String> {
+    if config.static_exclude_globs.is_empty() {
+        return Ok(String::new());
+    }
+
+    // In `unzip` patterns, `*` also matches `/`. So `*.map` matches source maps in
+    // subdirectories as well.
+    let patterns = config
+        .static_exclude_globs
+        .iter()
+        .map(|glob| format!(" '{static_dir_in_zip}{glob}'"))
+        .collect::<String>();
+
+    // `unzip -Z1` lists the matching entries, and exits with 11 if there are none.
+    let excluded = remote.execute_command_capture(
+        RemoteCommand::new(
+            format!("unzip -Z1 '{remote_zip_path}'{patterns}"),
+            "List which files will be left out of the static directory, for the log.",
+        )
+        .allow_exit_codes(&[11]),
+    )?;
+    if excluded.trim().is_empty() {
+        info!("No files in the static directory match the exclude globs");
+    }
+    for entry in excluded.lines() {
+        info!("Excluding `{}` from the static directory", entry);
+    }
+
+    Ok(format!(" -x{patterns}"))
+}
+
+fn write_deploy_info(config: &Config, args: &Args, remote: &Remote) -> Result<()> {
+    info!("Writing `{}`", DEPLOY_INFO_FILE);
+    let deploy_info = DeployInfo::for_deploy(config)?;
+
+    let mut deploy_info_path = config.rss_r_production_directory.clone();
+    deploy_info_path.push(DEPLOY_INFO_FILE);
+    let staged = StagedUpload::new(config, args, remote, DEPLOY_INFO_FILE)?;
+    let written = remote
+        .upload_bytes(
+            deploy_info.to_ron()?.as_bytes(),
+            staged.path(),
+            "Upload the record of this deploy.",
+        )
+        .and_then(|_| {
+            remote.execute_command(RemoteCommand::new(
+                format!("sudo mv '{}' '{deploy_info_path}'", staged.path()),
+                "Put the record of this deploy next to the executable, so `--show-deployed` can find it.",
+            ))
+        });
+    pause_if_failed(config, args, remote, written)?;
+    remote.execute_command(RemoteCommand::new(
+        format!("sudo rm -f '{}'", in_progress_path(config)),
+        "The deploy is done, a next one should make new backups.",
+    ))?;
+
+    deploy_info.log();
+
+    Ok(())
+}
+
+pub fn show_deployed(config: &Config, remote: &Remote) -> Result<()> {
+    let deploy_info = read_deployed_info(config, remote)?.ok_or_else(|| {
+        eyre!(
+            "No `{}` in `{}`, has there been a production deploy yet?",
+            DEPLOY_INFO_FILE,
+            config.rss_r_production_directory
+        )
+    })?;
+
+    info!(
+        "Currently deployed to `{}`:",
+        config.rss_r_production_directory
+    );
+    deploy_info.log();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    /// Deploys `version` over what is in `dir`, like `deploy_production` does, and stops before
+    /// it is verified.
+    fn deploy(config: &Config, remote: &Remote, version: &str) {
+        back_up_previous_version(config, remote).unwrap();
+        fs::write(config.production_executable(), version).unwrap();
+        fs::create_dir_all(config.production_static_directory()).unwrap();
+        fs::write(
+            config.production_static_directory().join("index.html"),
+            version,
+        )
+        .unwrap();
+    }
+
+    /// Like [deploy], and lets it fail.
+    fn deploy_and_fail(config: &Config, remote: &Remote, version: &str) {
+        deploy(config, remote, version);
+        restore_previous_version(config, remote).unwrap();
+    }
+
+    /// A production directory with `v1` deployed, and `v2.zip` and `v3.zip` next to it.
+    fn deployed_v1(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir())
+            .unwrap()
+            .join(format!("rss_r_deploy_{name}_{}", std::process::id()));
+        fs::create_dir_all(dir.join("static")).unwrap();
+        fs::write(dir.join("rss_r"), "v1").unwrap();
+        fs::write(dir.join("static").join("index.html"), "v1").unwrap();
+        fs::write(dir.join("v2.zip"), "v2").unwrap();
+        fs::write(dir.join("v3.zip"), "v3").unwrap();
+        dir
+    }
+
+    /// The deployed executable and `index.html`, and whether a deploy is in progress. Removes
+    /// the directory.
+    fn take_state(dir: &Utf8Path) -> (String, String, bool) {
+        let exe = fs::read_to_string(dir.join("rss_r")).unwrap();
+        let index = fs::read_to_string(dir.join("static").join("index.html")).unwrap();
+        let in_progress = dir.join(DEPLOY_IN_PROGRESS_FILE).exists();
+        fs::remove_dir_all(dir).unwrap();
+        (exe, index, in_progress)
+    }
+
+    fn config_for(dir: &Utf8Path, zip: &str) -> Config {
+        Config {
+            rss_r_zip: dir.join(zip),
+            rss_r_production_directory: dir.to_owned(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_retry_after_roll_back_still_rolls_back_to_previous_version() {
+        let dir = deployed_v1("retry");
+        let config = config_for(&dir, "v2.zip");
+        let remote = Remote::local();
+
+        deploy_and_fail(&config, &remote, "v2");
+        deploy_and_fail(&config, &remote, "v2");
+
+        assert_eq!(
+            take_state(&dir),
+            ("v1".to_string(), "v1".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_deploy_after_interrupted_deploy_keeps_backups() {
+        let dir = deployed_v1("interrupted");
+        let remote = Remote::local();
+
+        deploy(&config_for(&dir, "v2.zip"), &remote, "v2");
+        deploy_and_fail(&config_for(&dir, "v3.zip"), &remote, "v3");
+
+        assert_eq!(
+            take_state(&dir),
+            ("v1".to_string(), "v1".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn test_chown_command_follows_symlinked_directory() {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir().canonicalize().unwrap())
+            .unwrap()
+            .join(format!("rss_r_deploy_chown_{}", std::process::id()));
+        let release = dir.join("releases").join("1");
+        fs::create_dir_all(release.join("static")).unwrap();
+        symlink(&release, dir.join("current")).unwrap();
+
+        // Print the commands instead of running them, to see which paths they get.
+        let command = chown_command("rss", &dir.join("current"), true).replace("sudo ", "echo ");
+        let output = Command::new("sh").arg("-c").arg(&command).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(
+                "chown -h rss:rss {}\nchown -R rss:rss {}\n",
+                dir.join("current"),
+                release
+            )
+        );
+    }
+}
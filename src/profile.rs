@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+/// How many of the slowest commands to show.
+const SLOWEST_COMMANDS: usize = 5;
+
+/// What a [crate::remote::Remote] spent its time on. For `--profile`.
+#[derive(Default)]
+pub struct Stats {
+    pub connect_time: Duration,
+    /// Commands, uploads and the sudo prompt each open a channel.
+    pub channel_opens: u32,
+    /// Each command that was run.
+    pub commands: Vec<CommandRun>,
+    pub bytes_uploaded: u64,
+    pub upload_time: Duration,
+}
+
+impl Stats {
+    pub fn merge(&mut self, other: Stats) {
+        self.connect_time += other.connect_time;
+        self.channel_opens += other.channel_opens;
+        self.commands.extend(other.commands);
+        self.bytes_uploaded += other.bytes_uploaded;
+        self.upload_time += other.upload_time;
+    }
+}
+
+/// A command that was run on the target.
+pub struct CommandRun {
+    pub command: String,
+    pub exit_code: i32,
+    /// How long it took, including the round-trips.
+    pub duration: Duration,
+}
+
+/// Prints where the deploy spent its time.
+/// `round_trip` is how long a command that does nothing takes, which is all network overhead.
+pub fn print_profile(stats: &Stats, total: Duration, round_trip: Duration) {
+    let command_time: Duration = stats.commands.iter().map(|run| run.duration).sum();
+    let network_time = (round_trip * stats.commands.len() as u32).min(command_time);
+    let remote_work_time = command_time - network_time;
+    let other_time = total.saturating_sub(stats.connect_time + command_time + stats.upload_time);
+
+    println!("Profile:");
+    println!("  Total:              {}", seconds(total));
+    println!("  Connecting:         {}", seconds(stats.connect_time));
+    println!(
+        "  Commands:           {} for {} commands",
+        seconds(command_time),
+        stats.commands.len()
+    );
+    println!(
+        "    Round-trips:      ~{} ({} ms per command, measured)",
+        seconds(network_time),
+        round_trip.as_millis()
+    );
+    println!("    Remote work:      ~{}", seconds(remote_work_time));
+    println!(
+        "  Uploads:            {} for {:.1} MiB",
+        seconds(stats.upload_time),
+        stats.bytes_uploaded as f64 / (1024.0 * 1024.0)
+    );
+    println!("  Local and waiting:  {}", seconds(other_time));
+    println!("  SSH channels opened: {}", stats.channel_opens);
+
+    let mut slowest: Vec<_> = stats.commands.iter().collect();
+    slowest.sort_by_key(|run| std::cmp::Reverse(run.duration));
+    println!("  Slowest commands:");
+    for run in slowest.into_iter().take(SLOWEST_COMMANDS) {
+        println!("    {}  {}", seconds(run.duration), run.command);
+    }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
@@ -0,0 +1,849 @@
+use crate::archive::sha256_file;
+use crate::config::Config;
+use crate::plan::{Step, UploadSource};
+use crate::profile::{CommandRun, Stats};
+use camino::Utf8Path;
+use color_eyre::eyre::eyre;
+use color_eyre::{eyre::WrapErr, Result};
+use log::{debug, info, warn};
+use ssh2::{Channel, ExtendedData, OpenFlags, OpenType, Session};
+use std::cell::{Cell, Ref, RefCell};
+use std::fs::File;
+use std::io::{stdout, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// sudo forgets cached credentials after 5 minutes by default, on some systems 15.
+const SUDO_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What `flock` exits with when it could not get the lock in time. Set with `-E`, because by
+/// default it exits with 1, like a lot of the commands it runs.
+const LOCK_TIMEOUT_EXIT_CODE: i32 = 75;
+
+/// A command to run on the target.
+#[derive(Clone)]
+pub struct RemoteCommand {
+    pub command: String,
+    /// Why this command is run. Shown in the plan with `--explain`.
+    pub explanation: String,
+    /// Non-zero exit codes that only mean a warning, and don't fail the command.
+    pub allowed_exit_codes: Vec<i32>,
+    /// Whether this stops the service, or removes or overwrites files on the target.
+    /// Flagged in `--plan-json`, so tooling can ask for approval of these steps.
+    pub destructive: bool,
+    /// Run on the target itself, also when the deploy goes into a `remote_container`.
+    pub on_host: bool,
+}
+
+impl RemoteCommand {
+    pub fn new(command: impl Into<String>, explanation: impl Into<String>) -> Self {
+        RemoteCommand {
+            command: command.into(),
+            explanation: explanation.into(),
+            allowed_exit_codes: Vec::new(),
+            destructive: false,
+            on_host: false,
+        }
+    }
+
+    fn uses_sudo(&self) -> bool {
+        self.command.split_whitespace().any(|word| word == "sudo")
+    }
+
+    pub fn allow_exit_codes(mut self, codes: &[i32]) -> Self {
+        self.allowed_exit_codes.extend_from_slice(codes);
+        self
+    }
+
+    pub fn destructive(mut self) -> Self {
+        self.destructive = true;
+        self
+    }
+
+    pub fn on_host(mut self) -> Self {
+        self.on_host = true;
+        self
+    }
+
+    /// `unzip` exits with 1 for warnings, like files of which the permissions could not be set.
+    /// The extraction itself did succeed. 2 and up are real errors.
+    pub fn allow_unzip_warnings(self) -> Self {
+        self.allow_exit_codes(&[1])
+    }
+}
+
+/// An ssh session to the target, with the helpers to run commands and upload files over it.
+///
+/// Can also be created without connecting, to only plan a deploy. The commands and uploads
+/// are then recorded instead of executed.
+pub struct Remote {
+    /// `None` when only planning.
+    session: Option<Session>,
+    /// Set when the user pressed Ctrl+c. `None` if we could not listen for that signal,
+    /// in which case commands can't be gracefully interrupted.
+    interrupted: Option<Arc<AtomicBool>>,
+    /// Shell to run commands with, instead of the login shell of the user.
+    remote_shell: Option<String>,
+    /// Container on the target to run the commands in, as the login user.
+    container: Option<String>,
+    username: String,
+    /// When sudo's cached credentials were last refreshed. `None` if they were never primed,
+    /// which means sudo is not expected to ask for a password.
+    sudo_refreshed_at: Cell<Option<Instant>>,
+    /// Channel running the `flock` that holds the `remote_lock`, while it is held.
+    lock_channel: RefCell<Option<Channel>>,
+    /// Run the commands on this machine instead, without `sudo`.
+    #[cfg(test)]
+    local: bool,
+    planned_steps: RefCell<Vec<Step>>,
+    stats: RefCell<Stats>,
+}
+
+impl Remote {
+    pub fn connect(config: &Config, interrupted: Option<Arc<AtomicBool>>) -> Result<Self> {
+        let target = config.host_and_port();
+        info!("Connecting to `{}`", target);
+        let start = Instant::now();
+
+        let connect_timeout = Duration::from_secs(config.connect_timeout_secs);
+        let tcp = connect_with_timeout(&target, connect_timeout)
+            .with_context(|| format!("Could not connect to `{}`", target))?;
+        let mut session = Session::new()?;
+
+        session.set_tcp_stream(tcp);
+        // A half-open connection, or something that is not an ssh server, would otherwise
+        // never finish the handshake.
+        session.set_timeout(connect_timeout.as_millis().try_into().unwrap_or(u32::MAX));
+        session
+            .handshake()
+            .with_context(|| format!("ssh handshake with `{}` failed", target))?;
+        session.userauth_agent(&config.username)?;
+        // Commands can take as long as they need.
+        session.set_timeout(0);
+
+        info!("Logged in as `{}`", config.username);
+
+        Ok(Remote {
+            session: Some(session),
+            interrupted,
+            remote_shell: Some(config.remote_shell.clone()).filter(|shell| !shell.is_empty()),
+            container: Some(config.remote_container.clone())
+                .filter(|container| !container.is_empty()),
+            username: config.username.clone(),
+            sudo_refreshed_at: Cell::new(None),
+            lock_channel: RefCell::new(None),
+            #[cfg(test)]
+            local: false,
+            planned_steps: RefCell::new(Vec::new()),
+            stats: RefCell::new(Stats {
+                connect_time: start.elapsed(),
+                ..Stats::default()
+            }),
+        })
+    }
+
+    /// A remote that does not connect, and only records what would be done.
+    pub fn plan() -> Self {
+        Remote {
+            session: None,
+            interrupted: None,
+            remote_shell: None,
+            container: None,
+            username: String::new(),
+            sudo_refreshed_at: Cell::new(None),
+            lock_channel: RefCell::new(None),
+            #[cfg(test)]
+            local: false,
+            planned_steps: RefCell::new(Vec::new()),
+            stats: RefCell::new(Stats::default()),
+        }
+    }
+
+    /// A remote that runs the commands on this machine with `sh`, leaving out `sudo`. To test
+    /// what a sequence of commands does to a directory.
+    #[cfg(test)]
+    pub fn local() -> Self {
+        Remote {
+            local: true,
+            ..Remote::plan()
+        }
+    }
+
+    pub fn is_plan(&self) -> bool {
+        #[cfg(test)]
+        if self.local {
+            return false;
+        }
+        self.session.is_none()
+    }
+
+    /// `None` when only planning.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Asks for the sudo password, and gives it to sudo on the target once. After that, sudo
+    /// uses its cached credentials, so the following commands don't prompt for the password.
+    /// The cache is refreshed before sudo commands, so it doesn't expire during long deploys.
+    pub fn prime_sudo(&self, username: &str) -> Result<()> {
+        let command = RemoteCommand::new(
+            "sudo -S -v -p ''",
+            "Cache the sudo credentials, so later commands don't ask for the password.",
+        );
+        let Some(session) = &self.session else {
+            self.planned_steps.borrow_mut().push(Step::Command(command));
+            return Ok(());
+        };
+
+        let password = rpassword::prompt_password(format!("[sudo] password for {username}: "))?;
+
+        // With a container, sudo is used both on the target and inside the container.
+        let mut commands = vec![command.clone()];
+        if self.container.is_some() {
+            commands.insert(0, command.on_host());
+        }
+        for command in &commands {
+            let mut channel = session.channel_session()?;
+            self.stats.borrow_mut().channel_opens += 1;
+            channel.exec(&self.wrap_in_shell(command))?;
+            // `-S` makes sudo read the password from stdin.
+            channel.write_all(password.as_bytes())?;
+            channel.write_all(b"\n")?;
+            channel.send_eof()?;
+
+            let mut error_output = String::new();
+            channel.stderr().read_to_string(&mut error_output)?;
+            channel.wait_close()?;
+
+            if channel.exit_status()? != 0 {
+                return Err(eyre!(
+                    "sudo did not accept the password: {}",
+                    error_output.trim()
+                ));
+            }
+        }
+
+        self.sudo_refreshed_at.set(Some(Instant::now()));
+
+        // Without a terminal, sudo's default `timestamp_type=tty` caches the credentials for the
+        // parent process only. Then commands in a shell of their own, like `a || sudo b`, would
+        // still need the password. Better to find out now than halfway through a deploy.
+        let check = RemoteCommand::new(
+            "sh -c 'sudo -n true'",
+            "Check that the cached sudo credentials work in other processes as well.",
+        );
+        for command in &commands {
+            let check = RemoteCommand {
+                on_host: command.on_host,
+                ..check.clone()
+            };
+            if self.execute_command_status(&check)? != 0 {
+                return Err(eyre!(
+                    "sudo on the target only caches the password for a single process. Add `Defaults:{} timestamp_type=global` to its sudoers, or use `NOPASSWD` and unset `sudo_requires_password`",
+                    username
+                ));
+            }
+        }
+        info!("sudo credentials cached");
+
+        Ok(())
+    }
+
+    /// Refreshes sudo's cached credentials, if they were primed and the command needs them.
+    fn refresh_sudo_if_needed(&self, command: &RemoteCommand) -> Result<()> {
+        let Some(refreshed_at) = self.sudo_refreshed_at.get() else {
+            return Ok(());
+        };
+        // Commands in a container are always started with sudo.
+        let uses_sudo = command.uses_sudo() || self.container.is_some();
+        if !uses_sudo || refreshed_at.elapsed() < SUDO_REFRESH_INTERVAL {
+            return Ok(());
+        }
+
+        // Record the time first, otherwise refreshing would try to refresh itself.
+        self.sudo_refreshed_at.set(Some(Instant::now()));
+        // `-n`: fail instead of prompting, when the credentials have already expired.
+        let refresh = RemoteCommand::new("sudo -n -v", "Refresh the cached sudo credentials.");
+        if self.container.is_some() {
+            self.execute_command_capture(refresh.clone().on_host())
+                .context("Cached sudo credentials expired")?;
+        }
+        self.execute_command_capture(refresh)
+            .context("Cached sudo credentials expired")?;
+
+        Ok(())
+    }
+
+    /// Executes a given command.
+    /// Prints the stdout and stderr output as it arrives.
+    /// Returns an error if the command had a non-zero exit code, that is not one of its
+    /// allowed exit codes.
+    pub fn execute_command(&self, command: RemoteCommand) -> Result<()> {
+        let exit_code = self.execute_command_status(&command)?;
+
+        if exit_code == 0 {
+            Ok(())
+        } else if command.allowed_exit_codes.contains(&exit_code) {
+            warn!(
+                "command `{}` exited with code `{}`, continuing because that is only a warning",
+                command.command, exit_code
+            );
+            Ok(())
+        } else {
+            Err(eyre!(
+                "command `{}` failed with exit code `{}`",
+                command.command,
+                exit_code
+            ))
+        }
+    }
+
+    /// Executes a given command, and returns its exit code.
+    /// Prints the stdout and stderr output as it arrives.
+    /// When only planning, the exit code is 0.
+    pub fn execute_command_status(&self, command: &RemoteCommand) -> Result<i32> {
+        #[cfg(test)]
+        if self.local {
+            return self.run_locally(command).map(|(exit_code, _)| exit_code);
+        }
+        let Some(session) = &self.session else {
+            self.planned_steps
+                .borrow_mut()
+                .push(Step::Command(command.clone()));
+            return Ok(0);
+        };
+
+        self.refresh_sudo_if_needed(command)?;
+
+        let start = Instant::now();
+        let mut channel = session.channel_session()?;
+        self.stats.borrow_mut().channel_opens += 1;
+        // Will merge stdout and stderr data into stdout.
+        channel.handle_extended_data(ExtendedData::Merge)?;
+
+        channel.exec(&self.wrap_in_shell(command))?;
+
+        while !channel.eof() {
+            let mut bytes = [0; 32];
+
+            let amount = channel.read(&mut bytes)?;
+            stdout().write_all(&bytes[0..amount])?;
+
+            stdout().flush()?;
+
+            if self.take_interrupt() {
+                info!("Stopping remote command...");
+
+                // Ask the remote to stop the command.
+                // TODO (Wybe 2022-10-17): This does not work yet. How do we stop an ongoing command in this case?
+                channel.send_eof()?;
+                channel.close()?;
+                break;
+            }
+        }
+
+        channel.wait_close()?;
+        let exit_code = channel.exit_status()?;
+        self.record_command(command, exit_code, start);
+
+        Ok(exit_code)
+    }
+
+    /// Executes a given command, and returns its stdout output.
+    /// Returns an error containing the stderr output if the command had a non-zero exit code,
+    /// that is not one of its allowed exit codes.
+    /// When only planning, the output is empty.
+    pub fn execute_command_capture(&self, command: RemoteCommand) -> Result<String> {
+        #[cfg(test)]
+        if self.local {
+            let (exit_code, output) = self.run_locally(&command)?;
+            return if exit_code == 0 || command.allowed_exit_codes.contains(&exit_code) {
+                Ok(output)
+            } else {
+                Err(eyre!(
+                    "command `{}` failed with exit code `{}`",
+                    command.command,
+                    exit_code
+                ))
+            };
+        }
+        let Some(session) = &self.session else {
+            self.planned_steps.borrow_mut().push(Step::Command(command));
+            return Ok(String::new());
+        };
+
+        self.refresh_sudo_if_needed(&command)?;
+
+        let start = Instant::now();
+        let mut channel = session.channel_session()?;
+        self.stats.borrow_mut().channel_opens += 1;
+        channel.exec(&self.wrap_in_shell(&command))?;
+
+        let mut output = String::new();
+        channel.read_to_string(&mut output)?;
+        let mut error_output = String::new();
+        channel.stderr().read_to_string(&mut error_output)?;
+
+        channel.wait_close()?;
+        let exit_code = channel.exit_status()?;
+        self.record_command(&command, exit_code, start);
+
+        if exit_code == 0 || command.allowed_exit_codes.contains(&exit_code) {
+            Ok(output)
+        } else {
+            Err(eyre!(
+                "command `{}` failed with exit code `{}`: {}",
+                command.command,
+                exit_code,
+                error_output.trim()
+            ))
+        }
+    }
+
+    pub fn upload_file(
+        &self,
+        file: &Utf8Path,
+        remote_path: &Utf8Path,
+        explanation: &str,
+    ) -> Result<()> {
+        if self.is_plan() {
+            self.planned_steps.borrow_mut().push(Step::Upload {
+                source: UploadSource::File(file.to_owned()),
+                remote_path: remote_path.to_owned(),
+                explanation: explanation.to_string(),
+            });
+            return Ok(());
+        }
+
+        let bytes = read_upload_source(file)?;
+
+        info!("Uploading `{}` to `{}`", file, remote_path);
+
+        self.upload_bytes(&bytes, remote_path, explanation)
+    }
+
+    /// Like [Remote::upload_file], but only readable by the login user. For files that can have
+    /// secrets in them. Only new files get that mode, so `remote_path` should not exist yet.
+    pub fn upload_private_file(
+        &self,
+        file: &Utf8Path,
+        remote_path: &Utf8Path,
+        explanation: &str,
+    ) -> Result<()> {
+        if self.is_plan() {
+            return self.upload_file(file, remote_path, explanation);
+        }
+
+        let bytes = read_upload_source(file)?;
+        info!("Uploading `{}` to `{}`", file, remote_path);
+        self.upload_bytes_with_mode(&bytes, remote_path, 0o600, explanation)
+    }
+
+    /// Uploads over SFTP, continuing from where a previous, interrupted, upload of the same
+    /// file stopped. Assumes that a partial file at `remote_path` came from this same `file`.
+    /// Afterward, the size of the remote file is checked against the local one. Use
+    /// [Remote::upload_mismatch] to also check the content.
+    pub fn upload_file_resumable(
+        &self,
+        file: &Utf8Path,
+        remote_path: &Utf8Path,
+        explanation: &str,
+    ) -> Result<()> {
+        let Some(session) = &self.session else {
+            self.planned_steps.borrow_mut().push(Step::Upload {
+                source: UploadSource::File(file.to_owned()),
+                remote_path: remote_path.to_owned(),
+                explanation: explanation.to_string(),
+            });
+            return Ok(());
+        };
+
+        let bytes = read_upload_source(file)?;
+        let total = bytes.len() as u64;
+
+        let start = Instant::now();
+        let sftp = session.sftp()?;
+        let existing = sftp
+            .stat(remote_path.as_std_path())
+            .ok()
+            .and_then(|stat| stat.size)
+            .unwrap_or(0);
+        // A bigger remote file can't be a partial upload of this one.
+        let offset = if existing <= total { existing } else { 0 };
+
+        if offset > 0 {
+            info!(
+                "Resuming upload of `{}` to `{}` at {} of {} bytes",
+                file, remote_path, offset, total
+            );
+        } else {
+            info!("Uploading `{}` to `{}`", file, remote_path);
+        }
+
+        let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        if offset == 0 {
+            flags |= OpenFlags::TRUNCATE;
+        }
+        let mut remote_file =
+            sftp.open_mode(remote_path.as_std_path(), flags, 0o644, OpenType::File)?;
+        remote_file.seek(SeekFrom::Start(offset))?;
+        remote_file.write_all(&bytes[offset as usize..])?;
+        // Not every server supports fsync. The size and hash checks below are what count.
+        remote_file.fsync().ok();
+        drop(remote_file);
+        self.record_upload(total - offset, start);
+
+        let uploaded = sftp
+            .stat(remote_path.as_std_path())?
+            .size
+            .unwrap_or_default();
+        if uploaded != total {
+            return Err(eyre!(
+                "Uploaded `{}` is {} bytes, expected {}",
+                remote_path,
+                uploaded,
+                total
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Compares the SHA-256 of an uploaded file with the local one. Returns the SHA-256 of the
+    /// uploaded file if they differ, `None` if they match or when only planning.
+    pub fn upload_mismatch(
+        &self,
+        file: &Utf8Path,
+        remote_path: &Utf8Path,
+    ) -> Result<Option<String>> {
+        let local_hash = sha256_file(file)?;
+        let remote_hash = self.remote_sha256(remote_path)?;
+        if self.is_plan() || remote_hash == local_hash {
+            Ok(None)
+        } else {
+            Ok(Some(remote_hash))
+        }
+    }
+
+    /// SHA-256 of a file on the target, as a lowercase hex string.
+    pub fn remote_sha256(&self, remote_path: &Utf8Path) -> Result<String> {
+        let output = self.execute_command_capture(RemoteCommand::new(
+            format!("sha256sum '{remote_path}'"),
+            "Check that the file arrived intact.",
+        ))?;
+        if self.is_plan() {
+            return Ok(output);
+        }
+
+        // Output is `<hash>  <path>`.
+        output
+            .split_whitespace()
+            .next()
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("Unexpected output from `sha256sum`: `{}`", output))
+    }
+
+    pub fn upload_bytes(
+        &self,
+        bytes: &[u8],
+        remote_path: &Utf8Path,
+        explanation: &str,
+    ) -> Result<()> {
+        self.upload_bytes_with_mode(bytes, remote_path, 0o644, explanation)
+    }
+
+    fn upload_bytes_with_mode(
+        &self,
+        bytes: &[u8],
+        remote_path: &Utf8Path,
+        mode: i32,
+        explanation: &str,
+    ) -> Result<()> {
+        let Some(session) = &self.session else {
+            self.planned_steps.borrow_mut().push(Step::Upload {
+                source: UploadSource::Bytes(bytes.to_vec()),
+                remote_path: remote_path.to_owned(),
+                explanation: explanation.to_string(),
+            });
+            return Ok(());
+        };
+
+        let start = Instant::now();
+        let mut remote_file =
+            session.scp_send(remote_path.as_std_path(), mode, bytes.len() as u64, None)?;
+
+        remote_file.write_all(bytes)?;
+        remote_file.send_eof()?;
+        remote_file.wait_eof()?;
+        remote_file.close()?;
+        remote_file.wait_close()?;
+        self.record_upload(bytes.len() as u64, start);
+
+        Ok(())
+    }
+
+    /// Runs the command with `sh` on this machine, with `sudo` only running what it is given.
+    /// Returns the exit code and stdout.
+    #[cfg(test)]
+    fn run_locally(&self, command: &RemoteCommand) -> Result<(i32, String)> {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("sudo() {{ \"$@\"; }}\n{}", command.command))
+            .output()?;
+        let exit_code = output.status.code().unwrap_or(-1);
+        self.record_command(command, exit_code, Instant::now());
+        Ok((
+            exit_code,
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        ))
+    }
+
+    /// Takes the lock on `lock_file` with `flock`, waiting at most `timeout_secs` for it. It is
+    /// held by a `flock` that keeps running on the target, until the returned guard is dropped
+    /// or the connection is lost. The lock is taken with `sudo`, because the file is created in
+    /// a directory only root can write to.
+    pub fn lock(&self, lock_file: &Utf8Path, timeout_secs: u64) -> Result<RemoteLock<'_>> {
+        // `cat` keeps running until we close its stdin, and `flock` holds the lock until then.
+        let command = RemoteCommand::new(
+            format!(
+                "sudo flock -w {timeout_secs} -E {LOCK_TIMEOUT_EXIT_CODE} '{lock_file}' -c 'echo locked && cat'"
+            ),
+            "Hold the lock on the production directory until the deploy is done, so other deploys and manual operations that take it wait.",
+        );
+        let Some(session) = &self.session else {
+            self.planned_steps.borrow_mut().push(Step::Lock(command));
+            return Ok(RemoteLock { remote: self });
+        };
+
+        info!("Taking the lock `{}`", lock_file);
+        self.refresh_sudo_if_needed(&command)?;
+        let start = Instant::now();
+        let mut channel = session.channel_session()?;
+        self.stats.borrow_mut().channel_opens += 1;
+        channel.exec(&self.wrap_in_shell(&command))?;
+
+        // Only `locked` is printed, once the lock is taken.
+        let mut output = [0; 7];
+        if channel.read_exact(&mut output).is_err() || &output != b"locked\n" {
+            channel.wait_close()?;
+            let exit_code = channel.exit_status()?;
+            self.record_command(&command, exit_code, start);
+            return Err(if exit_code == LOCK_TIMEOUT_EXIT_CODE {
+                eyre!(
+                    "Could not get the lock `{}` on the target within {} seconds. Another deploy, or someone on the target, is holding it",
+                    lock_file,
+                    timeout_secs
+                )
+            } else {
+                eyre!(
+                    "Could not take the lock `{}`, `flock` exited with code `{}`",
+                    lock_file,
+                    exit_code
+                )
+            });
+        }
+        self.record_command(&command, 0, start);
+
+        *self.lock_channel.borrow_mut() = Some(channel);
+        Ok(RemoteLock { remote: self })
+    }
+
+    /// The steps recorded while only planning.
+    pub fn planned_steps(&self) -> Ref<'_, Vec<Step>> {
+        self.planned_steps.borrow()
+    }
+
+    /// Commands are written in `sh` syntax. When the user's login shell is something else, the
+    /// `remote_shell` makes sure they are still interpreted the same way.
+    /// With a `remote_container`, the command is run inside the container as the login user.
+    fn wrap_in_shell(&self, command: &RemoteCommand) -> String {
+        let mut command_line = command.command.clone();
+        if let (Some(container), false) = (&self.container, command.on_host) {
+            command_line = in_container(&command_line, container, &self.username);
+        }
+        match &self.remote_shell {
+            Some(shell) => format!("{} -c {}", shell, shell_quote(&command_line)),
+            None => command_line,
+        }
+    }
+
+    /// Logs the exit code at debug level, so the log shows how every command went, not only the
+    /// failed ones. And keeps them for `--profile` and `--report`.
+    fn record_command(&self, command: &RemoteCommand, exit_code: i32, start: Instant) {
+        let duration = start.elapsed();
+        debug!(
+            "command `{}` exited {} in {}ms",
+            command.command,
+            exit_code,
+            duration.as_millis()
+        );
+        self.stats.borrow_mut().commands.push(CommandRun {
+            command: command.command.clone(),
+            exit_code,
+            duration,
+        });
+    }
+
+    fn record_upload(&self, bytes: u64, start: Instant) {
+        let mut stats = self.stats.borrow_mut();
+        stats.channel_opens += 1;
+        stats.bytes_uploaded += bytes;
+        stats.upload_time += start.elapsed();
+    }
+
+    pub fn stats(&self) -> Ref<'_, Stats> {
+        self.stats.borrow()
+    }
+
+    /// Counts what another connection did as part of the same deploy, like the uploads of the
+    /// additional archives.
+    pub fn add_stats(&self, other: Remote) {
+        self.stats.borrow_mut().merge(other.stats.into_inner());
+    }
+
+    /// How long running a command that does nothing takes, which is all network overhead.
+    /// The fastest of a few tries, to leave out hiccups. Not recorded in the stats.
+    pub fn measure_round_trip(&self) -> Result<Duration> {
+        let Some(session) = &self.session else {
+            return Ok(Duration::ZERO);
+        };
+
+        let mut fastest = Duration::MAX;
+        for _ in 0..3 {
+            let start = Instant::now();
+            let mut channel = session.channel_session()?;
+            channel.exec("true")?;
+            channel.read_to_end(&mut Vec::new())?;
+            channel.wait_close()?;
+            fastest = fastest.min(start.elapsed());
+        }
+
+        Ok(fastest)
+    }
+
+    /// Returns whether Ctrl+c was pressed since the last time this was called.
+    pub fn take_interrupt(&self) -> bool {
+        self.interrupted
+            .as_ref()
+            .is_some_and(|interrupted| interrupted.swap(false, Ordering::Relaxed))
+    }
+}
+
+/// Like [TcpStream::connect], but gives up after `timeout` instead of the OS default, which can be
+/// minutes when a firewall drops the packets. Tries every address the host resolves to.
+fn connect_with_timeout(target: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut last_error = None;
+    for address in target.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => eyre!("`{}` did not resolve to any address", target),
+    })
+}
+
+/// Reads a file to upload. Refuses empty files: they are never meant to be deployed, and
+/// more likely come from a broken build. An empty upload would also leave the target with an
+/// empty file that only fails once the app reads it.
+fn read_upload_source(file: &Utf8Path) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(file)
+        .and_then(|mut local_file| local_file.read_to_end(&mut bytes))
+        .with_context(|| format!("Could not read `{}`", file))?;
+    if bytes.is_empty() {
+        return Err(eyre!("`{}` is empty, refusing to upload it", file));
+    }
+    Ok(bytes)
+}
+
+/// Holds a lock on the target, taken with [Remote::lock]. Released when dropped.
+pub struct RemoteLock<'a> {
+    remote: &'a Remote,
+}
+
+impl Drop for RemoteLock<'_> {
+    fn drop(&mut self) {
+        let Some(mut channel) = self.remote.lock_channel.borrow_mut().take() else {
+            return;
+        };
+        // Closing stdin ends `cat`, and with it `flock`.
+        let released = channel
+            .send_eof()
+            .and_then(|_| channel.wait_eof())
+            .and_then(|_| channel.wait_close());
+        match released {
+            Ok(()) => info!("Released the lock"),
+            Err(e) => warn!(
+                "Could not release the lock, it is released when we disconnect: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// Runs a command line inside a container on the target, as `username`.
+pub fn in_container(command_line: &str, container: &str, username: &str) -> String {
+    // `--wait` makes `systemd-run` exit with the exit code of the command.
+    format!(
+        "sudo systemd-run --machine={} --uid={} --quiet --pipe --wait --collect -- /bin/sh -c {}",
+        shell_quote(container),
+        shell_quote(username),
+        shell_quote(command_line)
+    )
+}
+
+/// Quotes a string so that a POSIX shell sees it as a single argument, with no expansions.
+pub fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// A `systemctl` command that never starts a pager. Some systemd versions start one even when
+/// there is no terminal, which then waits forever for input.
+pub fn systemctl(args: &str) -> String {
+    format!("SYSTEMD_PAGER= PAGER=cat systemctl --no-pager {args}")
+}
+
+/// Like [systemctl], but run with `sudo`. The environment is set outside of `sudo`, so sudoers
+/// rules that only allow `systemctl` still match. `--no-pager` is what counts when `sudo` drops
+/// the environment.
+pub fn sudo_systemctl(args: &str) -> String {
+    format!("SYSTEMD_PAGER= PAGER=cat sudo systemctl --no-pager {args}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camino::Utf8PathBuf;
+    use std::fs;
+
+    #[test]
+    fn test_read_upload_source_refuses_empty_file() {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir()).unwrap();
+        let empty = dir.join(format!("rss_r_deploy_empty_{}", std::process::id()));
+        fs::write(&empty, "").unwrap();
+
+        let result = read_upload_source(&empty);
+        fs::remove_file(&empty).unwrap();
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("is empty"), "{error}");
+    }
+
+    #[test]
+    fn test_read_upload_source_reads_file() {
+        let dir = Utf8PathBuf::try_from(std::env::temp_dir()).unwrap();
+        let file = dir.join(format!("rss_r_deploy_upload_{}", std::process::id()));
+        fs::write(&file, "(port: 8001)").unwrap();
+
+        let result = read_upload_source(&file);
+        fs::remove_file(&file).unwrap();
+
+        assert_eq!(result.unwrap(), b"(port: 8001)");
+    }
+}
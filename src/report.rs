@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::deploy_info::DeployInfo;
+use crate::remote::Remote;
+use camino::Utf8Path;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use serde::Serialize;
+use std::fs;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A record of a single deploy, for `--report`. Meant to be archived, so it is written as JSON
+/// that other tools can read.
+#[derive(Serialize)]
+struct DeployReport<'a> {
+    /// UTC, in RFC 3339 format.
+    started_at: String,
+    finished_at: String,
+    deployed_by: String,
+    /// `production` or `test`.
+    environment: &'a str,
+    target_host: String,
+    zip: &'a Utf8Path,
+    zip_sha256: String,
+    /// The configured `deploy_label`, or the git commit the zip was built from.
+    label: Option<String>,
+    commands: Vec<ReportedCommand<'a>>,
+    bytes_uploaded: u64,
+    /// `success`, or `failed`.
+    outcome: &'a str,
+    /// Why the deploy failed, if it did.
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReportedCommand<'a> {
+    command: &'a str,
+    exit_code: i32,
+    duration_ms: u128,
+}
+
+/// Writes the report of a deploy that started at `started_at`, and ended with `result`.
+/// `remote` is `None` when the deploy failed before connecting.
+pub fn write_report(
+    path: &Utf8Path,
+    config: &Config,
+    remote: Option<&Remote>,
+    production: bool,
+    started_at: OffsetDateTime,
+    result: &Result<()>,
+) -> Result<()> {
+    let deploy_info = DeployInfo::for_deploy(config)?;
+    let stats = remote.map(Remote::stats);
+
+    let report = DeployReport {
+        started_at: started_at.format(&Rfc3339)?,
+        finished_at: OffsetDateTime::now_utc().format(&Rfc3339)?,
+        deployed_by: deploy_info.deployed_by,
+        environment: if production { "production" } else { "test" },
+        target_host: config.host_and_port(),
+        zip: &config.rss_r_zip,
+        zip_sha256: deploy_info.zip_sha256,
+        label: deploy_info.label,
+        commands: stats
+            .iter()
+            .flat_map(|stats| &stats.commands)
+            .map(|run| ReportedCommand {
+                command: &run.command,
+                exit_code: run.exit_code,
+                duration_ms: run.duration.as_millis(),
+            })
+            .collect(),
+        bytes_uploaded: stats.as_ref().map_or(0, |stats| stats.bytes_uploaded),
+        outcome: if result.is_ok() { "success" } else { "failed" },
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+    };
+
+    let json = serde_json::to_string_pretty(&report).context("Could not convert report to JSON")?;
+    fs::write(path, json + "\n").with_context(|| format!("Could not write report `{}`", path))
+}
@@ -0,0 +1,268 @@
+use crate::config::Config;
+use crate::remote::{sudo_systemctl, systemctl, Remote, RemoteCommand};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt;
+
+/// `systemctl status` exits with 3 when the service is not running.
+const NOT_RUNNING_EXIT_CODE: i32 = 3;
+
+pub fn stop_service(config: &Config, remote: &Remote) -> Result<()> {
+    let service = &config.service_name;
+    info!("Stopping {} service", service);
+    remote.execute_command(
+        RemoteCommand::new(
+            sudo_systemctl(&format!("stop '{service}'")),
+            "Stop the service, as asked on the command line.",
+        )
+        .destructive(),
+    )?;
+
+    confirm_state(config, remote, false)
+}
+
+pub fn start_service(config: &Config, remote: &Remote) -> Result<()> {
+    let service = &config.service_name;
+    check_available_memory(config, remote)?;
+    info!("Starting {} service", service);
+    remote.execute_command(RemoteCommand::new(
+        sudo_systemctl(&format!("start '{service}'")),
+        "Start the service, as asked on the command line.",
+    ))?;
+
+    confirm_state(config, remote, true)
+}
+
+/// Checks that the target has at least `min_available_memory_mib` available, before the service
+/// is started. Otherwise it would be killed for lack of memory, which is hard to tell from the
+/// service failing by itself.
+pub fn check_available_memory(config: &Config, remote: &Remote) -> Result<()> {
+    if config.min_available_memory_mib == 0 {
+        return Ok(());
+    }
+
+    let meminfo = remote.execute_command_capture(RemoteCommand::new(
+        "grep MemAvailable /proc/meminfo",
+        "Check that there is enough memory to start the service.",
+    ))?;
+    if remote.is_plan() {
+        return Ok(());
+    }
+
+    // Like `MemAvailable:    1234567 kB`.
+    let available_mib = meminfo
+        .split_whitespace()
+        .nth(1)
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib / 1024)
+        .ok_or_else(|| {
+            eyre!(
+                "Unexpected output from `/proc/meminfo`: `{}`",
+                meminfo.trim()
+            )
+        })?;
+    info!("Available memory on the target: {} MiB", available_mib);
+
+    if available_mib >= config.min_available_memory_mib {
+        return Ok(());
+    }
+    if !config.abort_on_low_memory {
+        warn!(
+            "Less than the configured {} MiB of memory available, the {} service may be killed",
+            config.min_available_memory_mib, config.service_name
+        );
+        return Ok(());
+    }
+    Err(eyre!(
+        "Only {} MiB of memory available on the target, but {} MiB is needed to start the {} service. It is not started, start it with `service start` once there is enough memory",
+        available_mib,
+        config.min_available_memory_mib,
+        config.service_name
+    ))
+}
+
+/// The state of a service, from `systemctl show`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+    /// Like `active`, `inactive` or `failed`.
+    pub active_state: String,
+    /// Like `running`, `exited` or `dead`.
+    pub sub_state: String,
+    /// 0 when the service has no running process.
+    pub main_pid: u32,
+    /// How often systemd restarted the service since it was last started.
+    pub restarts: u32,
+    /// When the current process was started, as formatted by systemd. Empty if it never was.
+    pub started_at: String,
+}
+
+impl ServiceStatus {
+    const PROPERTIES: &'static str =
+        "ActiveState,SubState,MainPID,NRestarts,ExecMainStartTimestamp";
+
+    /// Gets the status of the service. `None` when only making a plan.
+    pub fn query(config: &Config, remote: &Remote) -> Result<Option<Self>> {
+        let service = &config.service_name;
+        let output = remote.execute_command_capture(RemoteCommand::new(
+            systemctl(&format!("show '{service}' --property={}", Self::PROPERTIES)),
+            "Get the state of the service.",
+        ))?;
+        if remote.is_plan() {
+            return Ok(None);
+        }
+        Self::parse(&output).map(Some)
+    }
+
+    /// Parses the `Property=value` lines of `systemctl show`.
+    pub fn parse(output: &str) -> Result<Self> {
+        let properties: HashMap<&str, &str> = output
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let number = |property: &str| -> Result<u32> {
+            // `NRestarts` is missing on systemd older than 235.
+            let Some(value) = properties.get(property) else {
+                return Ok(0);
+            };
+            value
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("`systemctl show` gave `{}={}`", property, value))
+        };
+
+        Ok(ServiceStatus {
+            active_state: properties
+                .get("ActiveState")
+                .ok_or_else(|| eyre!("Unexpected output from `systemctl show`: `{}`", output))?
+                .trim()
+                .to_string(),
+            sub_state: properties.get("SubState").unwrap_or(&"").trim().to_string(),
+            main_pid: number("MainPID")?,
+            restarts: number("NRestarts")?,
+            started_at: properties
+                .get("ExecMainStartTimestamp")
+                .unwrap_or(&"")
+                .trim()
+                .to_string(),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active_state == "active"
+    }
+
+    /// Active, with a process that is running.
+    pub fn is_running(&self) -> bool {
+        self.is_active() && self.sub_state == "running" && self.main_pid != 0
+    }
+}
+
+impl fmt::Display for ServiceStatus {
+    /// Like `active (running), pid 1234, 0 restarts since start (Thu 2024-01-04 12:00:00 UTC)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.active_state, self.sub_state)?;
+        if self.main_pid != 0 {
+            write!(f, ", pid {}", self.main_pid)?;
+        }
+        write!(f, ", {} restarts since start", self.restarts)?;
+        if !self.started_at.is_empty() {
+            write!(f, " ({})", self.started_at)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows the full `systemctl status`, with the last lines the service logged. For when it is not
+/// in the state it should be in.
+pub fn show_status_text(config: &Config, remote: &Remote) -> Result<()> {
+    let service = &config.service_name;
+    remote.execute_command(
+        RemoteCommand::new(
+            systemctl(&format!("status '{service}'")),
+            "Show what the service logged, to see why it is not in the expected state.",
+        )
+        .allow_exit_codes(&[NOT_RUNNING_EXIT_CODE]),
+    )
+}
+
+/// Logs the status of the service, and returns an error if it is not running or not stopped,
+/// as expected.
+fn confirm_state(config: &Config, remote: &Remote, should_be_active: bool) -> Result<()> {
+    let service = &config.service_name;
+    let Some(status) = ServiceStatus::query(config, remote)? else {
+        return Ok(());
+    };
+
+    info!("The {} service is {}", service, status);
+    let as_expected = if should_be_active {
+        status.is_running()
+    } else {
+        !status.is_active()
+    };
+    if !as_expected {
+        show_status_text(config, remote)?;
+        return Err(eyre!("The {} service is {}", service, status));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_running_service() {
+        let status = ServiceStatus::parse(
+            "ActiveState=active\nSubState=running\nMainPID=1234\nNRestarts=0\nExecMainStartTimestamp=Thu 2024-01-04 12:00:00 UTC\n",
+        )
+        .unwrap();
+
+        assert!(status.is_running());
+        assert_eq!(status.main_pid, 1234);
+        assert_eq!(
+            status.to_string(),
+            "active (running), pid 1234, 0 restarts since start (Thu 2024-01-04 12:00:00 UTC)"
+        );
+    }
+
+    #[test]
+    fn test_parse_failed_service() {
+        let status = ServiceStatus::parse(
+            "ActiveState=failed\nSubState=failed\nMainPID=0\nNRestarts=5\nExecMainStartTimestamp=\n",
+        )
+        .unwrap();
+
+        assert!(!status.is_active());
+        assert!(!status.is_running());
+        assert_eq!(status.restarts, 5);
+        assert_eq!(
+            status.to_string(),
+            "failed (failed), 5 restarts since start"
+        );
+    }
+
+    #[test]
+    fn test_parse_without_restart_count() {
+        // Like on systemd older than 235.
+        let status =
+            ServiceStatus::parse("ActiveState=active\nSubState=running\nMainPID=1234\n").unwrap();
+
+        assert_eq!(status.restarts, 0);
+        assert!(status.is_running());
+    }
+
+    #[test]
+    fn test_parse_refuses_malformed_output() {
+        let malformed = [
+            "ActiveState=active\nSubState=running\nMainPID=1234\nNRestarts=many\n",
+            "ActiveState=active\nSubState=running\nMainPID=\n",
+            "Failed to connect to bus\n",
+        ];
+        for output in malformed {
+            assert!(ServiceStatus::parse(output).is_err(), "{output}");
+        }
+    }
+}
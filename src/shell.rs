@@ -0,0 +1,121 @@
+use crate::remote::Remote;
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use std::io::{stdin, stdout, ErrorKind, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How long to wait when there is nothing to read from either side.
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+/// Opens an interactive shell on the target, and connects it to this terminal until the
+/// shell exits.
+pub fn interactive_shell(remote: &Remote) -> Result<()> {
+    let session = remote
+        .session()
+        .ok_or_else(|| eyre!("Cannot open a shell when only planning"))?;
+
+    let mut channel = session.channel_session()?;
+    let (columns, rows) = terminal_size().unwrap_or((80, 24));
+    channel.request_pty("xterm", None, Some((columns, rows, 0, 0)))?;
+    channel.shell()?;
+
+    // Stdin can only be read blocking, so it is done in a separate thread.
+    // That thread stays behind, blocked on the next read, but the deploy is done soon after.
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = [0; 1024];
+        loop {
+            match stdin().read(&mut bytes) {
+                Ok(0) | Err(_) => break,
+                Ok(amount) => {
+                    if sender.send(bytes[..amount].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    // Raw mode, so keys like Ctrl+c and the arrow keys go to the remote shell, and the
+    // typed text is only echoed by the remote.
+    let saved_terminal = stty(&["-g"]);
+    stty(&["raw", "-echo"]);
+    session.set_blocking(false);
+
+    let result = (|| -> Result<()> {
+        let mut bytes = [0; 1024];
+        while !channel.eof() {
+            let mut idle = true;
+
+            match channel.read(&mut bytes) {
+                Ok(amount) if amount > 0 => {
+                    stdout().write_all(&bytes[..amount])?;
+                    stdout().flush()?;
+                    idle = false;
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            if let Ok(input) = receiver.try_recv() {
+                write_all_non_blocking(&mut channel, &input)?;
+                idle = false;
+            }
+
+            if idle {
+                sleep(IDLE_WAIT);
+            }
+        }
+        Ok(())
+    })();
+
+    session.set_blocking(true);
+    match saved_terminal {
+        Some(saved) => stty(&[saved.trim()]),
+        None => stty(&["sane"]),
+    };
+    println!();
+
+    result?;
+    channel.wait_close()?;
+    Ok(())
+}
+
+pub fn write_all_non_blocking(writer: &mut impl Write, mut bytes: &[u8]) -> Result<()> {
+    while !bytes.is_empty() {
+        match writer.write(bytes) {
+            Ok(amount) => bytes = &bytes[amount..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => sleep(IDLE_WAIT),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Runs `stty` on this terminal, and returns its output. `None` if that did not work, for
+/// example when there is no terminal.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Columns and rows of this terminal.
+fn terminal_size() -> Option<(u32, u32)> {
+    let size = stty(&["size"])?;
+    let mut numbers = size.split_whitespace().map(|number| number.parse().ok());
+    let rows = numbers.next()??;
+    let columns = numbers.next()??;
+    Some((columns, rows))
+}
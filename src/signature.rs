@@ -0,0 +1,58 @@
+use crate::config::Config;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use log::info;
+use minisign_verify::{PublicKey, Signature};
+use std::fs;
+
+/// Verifies the minisign signatures of `rss_r_zip` and the `additional_archives`, if an
+/// `archive_public_key` is configured. Done locally, before connecting, so an unsigned or
+/// tampered zip never reaches the target.
+pub fn verify_archive_signature(config: &Config) -> Result<()> {
+    if config.archive_public_key.is_empty() {
+        return Ok(());
+    }
+    let public_key = PublicKey::from_base64(config.archive_public_key.trim())
+        .context("`archive_public_key` is not a valid minisign public key")?;
+
+    let signature_path = if config.archive_signature.as_str().is_empty() {
+        default_signature_path(&config.rss_r_zip)
+    } else {
+        config.archive_signature.clone()
+    };
+    verify_signature(&public_key, &config.rss_r_zip, &signature_path)?;
+    for archive in &config.additional_archives {
+        verify_signature(
+            &public_key,
+            &archive.zip,
+            &default_signature_path(&archive.zip),
+        )?;
+    }
+    Ok(())
+}
+
+/// Where `minisign -S` puts the signature by default.
+fn default_signature_path(zip: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{zip}.minisig"))
+}
+
+fn verify_signature(
+    public_key: &PublicKey,
+    zip: &Utf8Path,
+    signature_path: &Utf8Path,
+) -> Result<()> {
+    let signature = fs::read_to_string(signature_path)
+        .with_context(|| format!("Could not read signature `{}`", signature_path))?;
+    let signature = Signature::decode(&signature)
+        .with_context(|| format!("`{}` is not a valid minisign signature", signature_path))?;
+    let contents = fs::read(zip).with_context(|| format!("Could not read `{}`", zip))?;
+
+    // Legacy signatures are not accepted, current minisign versions don't make them anymore.
+    public_key
+        .verify(&contents, &signature, false)
+        .map_err(|e| eyre!("Signature of `{}` is not valid: {}", zip, e))?;
+
+    info!("Signature of `{}` is valid", zip);
+    Ok(())
+}
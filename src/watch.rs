@@ -0,0 +1,75 @@
+use camino::Utf8Path;
+use color_eyre::eyre::{OptionExt, WrapErr};
+use color_eyre::Result;
+use log::{error, info};
+use notify::{RecursiveMode, Watcher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a file needs to be left alone, before we consider it done changing.
+/// Keeps us from acting on a half-written zip.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// How often to check for Ctrl+c while waiting for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Calls `on_change` every time the file at `path` changed, until Ctrl+c is pressed.
+/// Errors from `on_change` are logged, and then we keep on watching.
+pub fn watch_file(
+    path: &Utf8Path,
+    interrupted: Option<&Arc<AtomicBool>>,
+    mut on_change: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    // The parent directory is watched instead of the file itself, because build tools often
+    // replace the file instead of writing to it. Which would end a watch on the file.
+    let file_name = path
+        .file_name()
+        .ok_or_eyre("Cannot watch file, path does not have file name.")?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_str().is_empty() => parent,
+        _ => Utf8Path::new("."),
+    };
+    // Canonical, so it can be compared with the paths in the events.
+    let watched_dir = parent
+        .canonicalize()
+        .with_context(|| format!("Could not find directory `{}`", parent))?;
+    let watched_path = watched_dir.join(file_name);
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&watched_dir, RecursiveMode::NonRecursive)?;
+
+    info!("Watching `{}` for changes. Press Ctrl+c to stop.", path);
+
+    let mut last_change: Option<Instant> = None;
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if event.paths.contains(&watched_path) {
+                    last_change = Some(Instant::now());
+                }
+            }
+            Ok(Err(e)) => error!("Error while watching `{}`: {}", path, e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if interrupted.is_some_and(|interrupted| interrupted.swap(false, Ordering::Relaxed)) {
+            info!("Stopped watching");
+            break;
+        }
+
+        if last_change.is_some_and(|changed| changed.elapsed() >= DEBOUNCE) && path.exists() {
+            last_change = None;
+
+            info!("`{}` changed", path);
+            if let Err(e) = on_change() {
+                error!("{:#}", e);
+            }
+            info!("Watching `{}` for changes. Press Ctrl+c to stop.", path);
+        }
+    }
+
+    Ok(())
+}
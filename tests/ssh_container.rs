@@ -0,0 +1,229 @@
+//! Deploys to a real ssh server, running in a container.
+//! Needs `docker`, `ssh-keygen`, `ssh-agent` and `ssh-add`, so it is ignored by default.
+//! Run it with `cargo test -- --ignored`.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+const IMAGE: &str = "lscr.io/linuxserver/openssh-server:latest";
+const USER: &str = "deploy";
+const TEST_DIR: &str = "/config/rss_r_test";
+const APP_CONFIG: &str = "(port: 8001)\n";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[test]
+#[ignore = "needs docker"]
+fn test_deploy_lands_in_the_expected_layout() {
+    let dir = std::env::temp_dir().join(format!("rss_r_deploy_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let key = dir.join("id_ed25519");
+    run(Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key));
+    let public_key = fs::read_to_string(key.with_extension("pub")).unwrap();
+
+    let agent = Agent::start(&dir, &key);
+    let container = Container::start(public_key.trim());
+    let port = container.wait_for_ssh();
+
+    write_zip(&dir.join("rss_r.zip"));
+    fs::write(dir.join("app_config.ron"), APP_CONFIG).unwrap();
+    fs::write(
+        dir.join("deploy_config.ron"),
+        format!(
+            r#"(
+    target_host: "127.0.0.1",
+    target_ip: {port},
+    username: "{USER}",
+    rss_r_zip: "rss_r.zip",
+    rss_r_target_test_dir: "{TEST_DIR}",
+    rss_r_test_config_file: "app_config.ron",
+    created_dir_mode: "700",
+    rss_r_production_directory: "/opt/rss_r",
+    rss_r_production_user: "{USER}",
+)"#
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_deploy"))
+        .arg("--no-config-save")
+        .current_dir(&dir)
+        .env("SSH_AUTH_SOCK", &agent.socket)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "deploy failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let modes = container.exec(&format!(
+        "cd '{TEST_DIR}' && stat -c '%a %U %n' . rss_r/rss_r rss_r/static/index.html rss_r/persistence"
+    ));
+    assert_eq!(
+        modes.lines().collect::<Vec<_>>(),
+        [
+            format!("700 {USER} ."),
+            format!("755 {USER} rss_r/rss_r"),
+            format!("644 {USER} rss_r/static/index.html"),
+            format!("700 {USER} rss_r/persistence"),
+        ]
+    );
+    assert_eq!(
+        container.exec(&format!(
+            "cat '{TEST_DIR}/rss_r/persistence/app_config.ron'"
+        )),
+        APP_CONFIG
+    );
+
+    drop(container);
+    drop(agent);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// A zip with the same layout as the one rss_r is released in.
+fn write_zip(path: &Path) {
+    let mut zip = ZipWriter::new(File::create(path).unwrap());
+    let executable = SimpleFileOptions::default().unix_permissions(0o755);
+    let file = SimpleFileOptions::default().unix_permissions(0o644);
+
+    zip.add_directory("rss_r/", executable).unwrap();
+    zip.start_file("rss_r/rss_r", executable).unwrap();
+    zip.write_all(b"#!/bin/sh\necho rss_r\n").unwrap();
+    zip.add_directory("rss_r/static/", executable).unwrap();
+    zip.start_file("rss_r/static/index.html", file).unwrap();
+    zip.write_all(b"<html></html>\n").unwrap();
+    zip.finish().unwrap();
+}
+
+/// Runs the command, and panics if it fails.
+fn run(command: &mut Command) -> Output {
+    let output = command
+        .output()
+        .unwrap_or_else(|e| panic!("Could not run {command:?}: {e}"));
+    if !output.status.success() {
+        panic!(
+            "{command:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    output
+}
+
+/// An ssh agent with only the test key, because the deploy logs in through the agent.
+struct Agent {
+    process: Child,
+    socket: PathBuf,
+}
+
+impl Agent {
+    fn start(dir: &Path, key: &Path) -> Self {
+        let socket = dir.join("agent.sock");
+        // `-D`: stay in the foreground, so it can be stopped by killing the process.
+        let process = Command::new("ssh-agent")
+            .arg("-D")
+            .arg("-a")
+            .arg(&socket)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Could not start ssh-agent");
+        let agent = Agent { process, socket };
+
+        let start = Instant::now();
+        while !agent.socket.exists() {
+            assert!(start.elapsed() < STARTUP_TIMEOUT, "ssh-agent did not start");
+            sleep(Duration::from_millis(50));
+        }
+        run(Command::new("ssh-add")
+            .arg("-q")
+            .arg(key)
+            .env("SSH_AUTH_SOCK", &agent.socket));
+
+        agent
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// An ssh server that lets `USER` in with the test key. Removed when dropped.
+struct Container {
+    id: String,
+}
+
+impl Container {
+    fn start(public_key: &str) -> Self {
+        let output = run(Command::new("docker").args([
+            "run",
+            "--detach",
+            "--publish",
+            "127.0.0.1::2222",
+            "--env",
+            &format!("USER_NAME={USER}"),
+            "--env",
+            &format!("PUBLIC_KEY={public_key}"),
+            IMAGE,
+        ]));
+        Container {
+            id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        }
+    }
+
+    /// Waits until the ssh server answers, and returns the port it is reachable on.
+    fn wait_for_ssh(&self) -> u16 {
+        let output = run(Command::new("docker").args(["port", &self.id, "2222"]));
+        let published = String::from_utf8_lossy(&output.stdout);
+        let port = published
+            .lines()
+            .next()
+            .and_then(|line| line.rsplit_once(':'))
+            .and_then(|(_, port)| port.trim().parse().ok())
+            .unwrap_or_else(|| panic!("Unexpected `docker port` output: {published}"));
+
+        let start = Instant::now();
+        loop {
+            if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let mut banner = [0; 4];
+                if stream.read_exact(&mut banner).is_ok() && &banner == b"SSH-" {
+                    return port;
+                }
+            }
+            assert!(
+                start.elapsed() < STARTUP_TIMEOUT,
+                "The ssh server did not start"
+            );
+            sleep(Duration::from_millis(500));
+        }
+    }
+
+    /// Runs a shell command in the container, and returns its output.
+    fn exec(&self, command: &str) -> String {
+        let output = run(Command::new("docker").args(["exec", &self.id, "sh", "-c", command]));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", "--force", &self.id])
+            .output();
+    }
+}